@@ -0,0 +1,114 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// An address block like 192.168.1.0/24 or fd00::/8. A bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    let host_bits = u32::from(bits - prefix);
+    (net ^ ip).checked_shr(host_bits).unwrap_or(0) == 0
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("{} is not an IP address or CIDR block", s))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= bits)
+                .ok_or_else(|| format!("{} has an invalid prefix length", s))?,
+            None => bits,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// Which source addresses may connect. The deny list wins, and a non-empty allow list
+// turns away everything it doesn't cover.
+#[derive(Default)]
+pub struct AccessList {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    // Err carries the reason for the log
+    pub fn check(&self, ip: IpAddr) -> Result<(), String> {
+        if let Some(block) = self.deny.iter().find(|block| block.contains(ip)) {
+            return Err(format!("denied by {}", block));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|block| block.contains(ip)) {
+            return Err("not on the allow list".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_blocks_and_bare_addresses() {
+        let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains(ip("192.168.1.77")));
+        assert!(!lan.contains(ip("192.168.2.1")));
+        assert!(lan.contains(ip("::ffff:192.168.1.5")));
+
+        let host: Cidr = "10.0.0.5".parse().unwrap();
+        assert!(host.contains(ip("10.0.0.5")));
+        assert!(!host.contains(ip("10.0.0.6")));
+
+        let everything: Cidr = "::/0".parse().unwrap();
+        assert!(everything.contains(ip("fe80::1")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("pi.local".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let access = AccessList {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.13.0/24".parse().unwrap()],
+        };
+        assert!(access.check(ip("10.1.2.3")).is_ok());
+        assert!(access.check(ip("10.0.13.4")).is_err());
+        assert!(access.check(ip("172.16.0.1")).is_err());
+        assert!(AccessList::default().check(ip("172.16.0.1")).is_ok());
+    }
+}
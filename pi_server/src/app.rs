@@ -1,99 +1,244 @@
-use util_bundle::UtilBundle;
+use crate::series::Series;
+use crate::{ClientControl, POLLING_PERIOD_MILLIS};
 
-const MAX_UTIL_WINDOW_N: usize = 60;
+use pitop_protocol::datagram::LinkStats;
+use pitop_protocol::{now_millis, Control, MAX_SAMPLE_INTERVAL_MILLIS, MIN_SAMPLE_INTERVAL_MILLIS};
+use util_bundle::{HostInfo, MetricGroup, UtilBundle};
+
+use std::time::Instant;
+
+// points kept per metric, a little over 17 minutes at the default 250ms interval
+const SERIES_CAPACITY: usize = 4096;
+
+const HEALTH_WARN_RATIO: f64 = 0.7;
+const HEALTH_CRITICAL_RATIO: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Ok,
+    Warn,
+    Critical,
+    // connected, but nothing heard for longer than the staleness timeout
+    Stale,
+    Disconnected,
+}
 
 pub struct App {
-    pub cpu_util: Vec<Vec<(f64, f64)>>,
-    pub network_tx: Vec<(f64, f64)>,
-    pub network_rx: Vec<(f64, f64)>,
-    pub gpu_power_draw: Vec<(f64, f64)>,
+    // filled in by the client's hello message
+    pub host: Option<HostInfo>,
+    pub peer: String,
+    pub connected: bool,
+    // said goodbye before the connection went, rather than just losing it
+    pub left: bool,
+    pub stale: bool,
+    pub last_seen: Instant,
+    // None once the connection is gone
+    pub control: Option<ClientControl>,
+    // datagram clients only, TCP can't lose samples
+    pub link: Option<LinkStats>,
+    // samples the ingest queue threw away because the display fell behind
+    pub dropped: u64,
+    // what we last asked the client for
+    pub sample_interval_ms: u64,
+    pub disabled_groups: Vec<MetricGroup>,
+    pub cpu_util: Vec<Series>,
+    pub network_tx: Series,
+    pub network_rx: Series,
+    pub gpu_power_draw: Series,
     pub gpu_power_limit: f64,
-    pub mem_util: Vec<(f64, f64)>,
+    pub mem_util: Series,
     pub mem_total_bytes: u64,
 }
 
 impl App {
     pub fn new() -> App {
         App {
+            host: None,
+            peer: String::new(),
+            connected: false,
+            left: false,
+            stale: false,
+            last_seen: Instant::now(),
+            control: None,
+            link: None,
+            dropped: 0,
+            sample_interval_ms: POLLING_PERIOD_MILLIS,
+            disabled_groups: vec![],
             cpu_util: vec![],
-            network_tx: vec![],
-            network_rx: vec![],
-            gpu_power_draw: vec![],
+            network_tx: Series::new(SERIES_CAPACITY),
+            network_rx: Series::new(SERIES_CAPACITY),
+            gpu_power_draw: Series::new(SERIES_CAPACITY),
             gpu_power_limit: 0.0,
-            mem_util: vec![],
+            mem_util: Series::new(SERIES_CAPACITY),
             mem_total_bytes: 0,
         }
     }
 
-    // TODO: Optimize if necessary
-    pub fn on_tick(&mut self, datapoint: UtilBundle) {
+    pub fn clear_charts(&mut self) {
+        self.cpu_util.clear();
+        for series in [&mut self.network_tx, &mut self.network_rx, &mut self.gpu_power_draw, &mut self.mem_util] {
+            *series = Series::new(SERIES_CAPACITY);
+        }
+    }
 
-        if self.cpu_util.len() > MAX_UTIL_WINDOW_N {
-            self.cpu_util.remove(0);
+    // hostname once the hello arrived, peer address until then
+    pub fn label(&self) -> &str {
+        match &self.host {
+            Some(host) => &host.hostname,
+            None => &self.peer,
         }
-        if self.network_tx.len() > MAX_UTIL_WINDOW_N {
-            self.network_tx.remove(0);
+    }
+
+    // mean utilization over all cores in the latest sample, 0-100
+    pub fn latest_cpu_average(&self) -> f64 {
+        let latest: Vec<f64> = self.cpu_util.iter().filter_map(Series::latest).collect();
+        if latest.is_empty() {
+            return 0.0;
         }
-        if self.network_rx.len() > MAX_UTIL_WINDOW_N {
-            self.network_rx.remove(0);
+        latest.iter().sum::<f64>() / latest.len() as f64
+    }
+
+    pub fn latest_mem_ratio(&self) -> f64 {
+        self.mem_util.latest().unwrap_or(0.0)
+    }
+
+    // when the newest point was taken
+    pub fn latest_at(&self) -> Option<f64> {
+        [&self.network_tx, &self.gpu_power_draw, &self.mem_util]
+            .into_iter()
+            .chain(&self.cpu_util)
+            .filter_map(Series::latest_at)
+            .reduce(f64::max)
+    }
+
+    pub fn latest_gpu_ratio(&self) -> f64 {
+        if self.gpu_power_limit == 0.0 { return 0.0; }
+
+        if let Some(active_draw) = self.gpu_power_draw.latest() {
+            (active_draw / self.gpu_power_limit).clamp(0.0, 1.0)
+        } else {
+            0.0
         }
-        if self.gpu_power_draw.len() > MAX_UTIL_WINDOW_N {
-            self.gpu_power_draw.remove(0);
+    }
+
+    // worst of cpu, memory and gpu load
+    pub fn health(&self) -> Health {
+        if !self.connected {
+            return Health::Disconnected;
         }
-        if self.mem_util.len() > MAX_UTIL_WINDOW_N {
-            self.mem_util.remove(0);
+        if self.stale {
+            return Health::Stale;
         }
 
-        while self.cpu_util.len() < datapoint.cpu_usage.len() {
-            self.cpu_util.push(vec![]);
+        let load = (self.latest_cpu_average() / 100.0)
+            .max(self.latest_mem_ratio())
+            .max(self.latest_gpu_ratio());
+        if load >= HEALTH_CRITICAL_RATIO {
+            Health::Critical
+        } else if load >= HEALTH_WARN_RATIO {
+            Health::Warn
+        } else {
+            Health::Ok
         }
+    }
 
-        datapoint.cpu_usage.iter().enumerate().for_each(|(idx, f)| {
-            self.cpu_util[idx].push((0 as f64, *f as f64))
-        });
+    // A failed write means the connection is going away, its reader thread reports the disconnect
+    fn send_control(&self, control: Control) {
+        if let Some(client) = &self.control {
+            let _ = client.send(&control);
+        }
+    }
 
-        self.network_tx.push((0 as f64, (datapoint.data_tx as f64) / 1024.0));
-        self.network_rx.push((0 as f64, (datapoint.data_rx as f64) / 1024.0));
-        self.gpu_power_draw.push((0 as f64, datapoint.gpu_power as f64));
-        self.gpu_power_limit = datapoint.gpu_power_limit;
-        // TODO: never divide by 0 (wont be an issue once sharing info between threads)
-        if datapoint.mem_total > 0 {
-            self.mem_util.push((
-                0 as f64,
-                (datapoint.mem_used as f64 / datapoint.mem_total as f64).min(1.0).max(0.0),
-            ));
-        } else {
-            self.mem_util.push((0.0, 0.0));
+    pub fn set_sample_interval(&mut self, millis: u64) {
+        self.sample_interval_ms = millis.clamp(MIN_SAMPLE_INTERVAL_MILLIS, MAX_SAMPLE_INTERVAL_MILLIS);
+        self.send_control(Control::SetSampleInterval { millis: self.sample_interval_ms });
+    }
+
+    pub fn toggle_metric_group(&mut self, group: MetricGroup) {
+        let enabled = self.disabled_groups.contains(&group);
+        self.disabled_groups.retain(|g| *g != group);
+        if !enabled {
+            self.disabled_groups.push(group);
         }
-        self.mem_total_bytes = datapoint.mem_total;
+        self.send_control(Control::SetMetricGroup { group, enabled });
+    }
+
+    pub fn request_sample(&self) {
+        self.send_control(Control::SampleNow);
+    }
+
+    // Re-sends our view of the client's settings, e.g. after it reconnected
+    pub fn sync_controls(&self) {
+        self.send_control(Control::SetSampleInterval { millis: self.sample_interval_ms });
+        for group in &self.disabled_groups {
+            self.send_control(Control::SetMetricGroup { group: *group, enabled: false });
+        }
+    }
+
+    // Any message from the client counts as a sign of life
+    pub fn on_seen(&mut self, now: Instant) {
+        self.last_seen = now;
+        self.stale = false;
+    }
+
+    // Pushes a gap into every series. The chart skips it, so missing data shows up as a gap
+    // instead of a fake idle machine.
+    pub fn on_gap(&mut self, at: u64) {
+        self.cpu_util.iter_mut().for_each(|core| core.push_gap(at));
+        self.network_tx.push_gap(at);
+        self.network_rx.push_gap(at);
+        self.gpu_power_draw.push_gap(at);
+        self.mem_util.push_gap(at);
+    }
+
+    pub fn on_tick(&mut self, datapoint: UtilBundle) {
+        // spooled samples replayed after an outage keep the time they were taken
+        let at = match datapoint.sampled_at_ms {
+            0 => now_millis(),
+            sampled_at_ms => sampled_at_ms,
+        };
+
         // There are a couple obvious ways to organize cpu_util data:
         // 1. [[core1], [core2], [core3], ...]
         // 2. [[datapoint1], [datapoint2], [datapoint3], ...]
-        // Organizing as 1. allows us to easily plot each core as its own dataset (and follows how other 
+        // Organizing as 1. allows us to easily plot each core as its own dataset (and follows how other
         // utils are stored)
-        self.cpu_util
-            .iter_mut()
-            .for_each(|v| v.iter_mut().rev().enumerate().for_each(|(idx, (x_t, _y_t))| *x_t = idx as f64));
-
-        self.network_tx
-            .iter_mut()
-            .rev()
-            .enumerate()
-            .for_each(|(i, (t, _y))| *t = i as f64);
-        self.network_rx
-            .iter_mut()
-            .rev()
-            .enumerate()
-            .for_each(|(i, (t, _y))| *t = i as f64);
-        self.gpu_power_draw
-            .iter_mut()
-            .rev()
-            .enumerate()
-            .for_each(|(i, (t, _y))| *t = i as f64);
-        self.mem_util
-            .iter_mut()
-            .rev()
-            .enumerate()
-            .for_each(|(i, (t, _y))| *t = i as f64);
-    }
-}
\ No newline at end of file
+        while self.cpu_util.len() < datapoint.cpu_usage.len() {
+            self.cpu_util.push(Series::new(SERIES_CAPACITY));
+        }
+
+        // groups the client was told to skip are gaps, not zeros
+        if datapoint.is_enabled(MetricGroup::Cpu) {
+            datapoint.cpu_usage.iter().enumerate().for_each(|(idx, f)| {
+                self.cpu_util[idx].push(at, *f as f64)
+            });
+        } else {
+            self.cpu_util.iter_mut().for_each(|core| core.push_gap(at));
+        }
+
+        if datapoint.is_enabled(MetricGroup::Network) {
+            self.network_tx.push(at, (datapoint.data_tx as f64) / 1024.0);
+            self.network_rx.push(at, (datapoint.data_rx as f64) / 1024.0);
+        } else {
+            self.network_tx.push_gap(at);
+            self.network_rx.push_gap(at);
+        }
+        if datapoint.is_enabled(MetricGroup::Gpu) {
+            self.gpu_power_draw.push(at, datapoint.gpu_power);
+            self.gpu_power_limit = datapoint.gpu_power_limit;
+        } else {
+            self.gpu_power_draw.push_gap(at);
+        }
+        // TODO: never divide by 0 (wont be an issue once sharing info between threads)
+        if !datapoint.is_enabled(MetricGroup::Memory) {
+            self.mem_util.push_gap(at);
+        } else if datapoint.mem_total > 0 {
+            self.mem_util.push(at, (datapoint.mem_used as f64 / datapoint.mem_total as f64).clamp(0.0, 1.0));
+        } else {
+            self.mem_util.push(at, 0.0);
+        }
+        if datapoint.is_enabled(MetricGroup::Memory) {
+            self.mem_total_bytes = datapoint.mem_total;
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::terminal::restore_terminal;
+use crate::ClientId;
+
+use std::backtrace::Backtrace;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::panic;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, TryLockError};
+
+use util_bundle::UtilBundle;
+
+// bundles kept for the report, enough to see what led up to a bad one
+const RECENT_BUNDLES: usize = 16;
+
+// What gets written when the server panics: the panic itself and the bundles received just
+// before it, which are usually what it choked on
+#[derive(Clone)]
+pub struct CrashReport {
+    path: PathBuf,
+    recent: Arc<Mutex<VecDeque<(ClientId, UtilBundle)>>>,
+}
+
+impl CrashReport {
+    pub fn new(path: PathBuf) -> CrashReport {
+        CrashReport { path, recent: Arc::new(Mutex::new(VecDeque::with_capacity(RECENT_BUNDLES))) }
+    }
+
+    pub fn record(&self, client: ClientId, bundle: &UtilBundle) {
+        let mut recent = self.recent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if recent.len() == RECENT_BUNDLES {
+            recent.pop_front();
+        }
+        recent.push_back((client, bundle.clone()));
+    }
+
+    // A panic anywhere puts the terminal back first, so neither the report's location nor the
+    // usual panic message is lost in the alternate screen
+    pub fn install_panic_hook(&self) {
+        let report = self.clone();
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            match report.write(&info.to_string(), &Backtrace::force_capture().to_string()) {
+                Ok(()) => eprintln!("Crash report written to {}", report.path.display()),
+                Err(e) => eprintln!("Failed to write crash report to {}: {}", report.path.display(), e),
+            }
+            default_hook(info);
+        }));
+    }
+
+    fn write(&self, panic: &str, backtrace: &str) -> io::Result<()> {
+        let mut contents = Vec::new();
+        writeln!(contents, "pi_server {} {}", env!("CARGO_PKG_VERSION"), panic)?;
+        writeln!(contents, "\n{}", backtrace)?;
+        // the panic may have struck while a bundle was being recorded
+        let recent = match self.recent.try_lock() {
+            Ok(recent) => recent,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return fs::write(&self.path, contents),
+        };
+        writeln!(contents, "Last {} bundles received, oldest first:", recent.len())?;
+        for (client, bundle) in recent.iter() {
+            writeln!(contents, "client {}: {}", client, serde_json::to_string(bundle)?)?;
+        }
+        fs::write(&self.path, contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_has_the_panic_and_the_last_bundles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("crash.log");
+        let report = CrashReport::new(path.clone());
+        for sampled_at_ms in 0..RECENT_BUNDLES as u64 + 4 {
+            report.record(sampled_at_ms % 2, &UtilBundle { sampled_at_ms, ..Default::default() });
+        }
+        report.write("panicked at src/app.rs:1:1:\nboom", "backtrace").unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("boom"));
+        let bundles: Vec<&str> = contents.lines().filter(|line| line.starts_with("client ")).collect();
+        assert_eq!(bundles.len(), RECENT_BUNDLES);
+        assert!(bundles[0].starts_with("client 0: ") && bundles[0].contains("\"sampled_at_ms\":4"));
+        assert!(bundles.last().unwrap().contains("\"sampled_at_ms\":19"));
+    }
+}
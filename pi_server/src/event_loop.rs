@@ -0,0 +1,735 @@
+use crate::ingest::IngestSender;
+use crate::shutdown::Shutdown;
+use crate::{to_message, ClientControl, ClientId, ClientMessage, ListenerPolicy, CLIENT_TIMEOUT_MILLIS};
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::IpAddr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+
+use pitop_protocol::auth::{self, AuthChallenge};
+use pitop_protocol::framing::FrameDecoder;
+use pitop_protocol::tls::ServerConnection;
+use pitop_protocol::{Envelope, EnvelopeWriter, MessageKind};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_CONNECTION: usize = 2;
+
+// a peer that stalls a handshake shouldn't hold a client slot for long
+const HANDSHAKE_TIMEOUT_MILLIS: u64 = 5_000;
+// timeouts are checked this often, it is also the longest a poll blocks
+const SWEEP_PERIOD_MILLIS: u64 = 1_000;
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+// control messages queued for a client that stopped reading, before it is dropped
+const MAX_QUEUED_BYTES: usize = 256 * 1024;
+// how long goodbyes get to reach the clients on shutdown
+const GOODBYE_FLUSH_MILLIS: u64 = 1_000;
+
+// All connections are served from one thread: sockets are non-blocking and a connection only
+// costs its buffers, so a Pi can watch hundreds of clients. Control messages from the tui are
+// queued to this thread instead of written from the tui thread.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn tcp(listener: std::net::TcpListener) -> io::Result<Listener> {
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Tcp(TcpListener::from_std(listener)))
+    }
+
+    pub fn unix(listener: std::os::unix::net::UnixListener) -> io::Result<Listener> {
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix(UnixListener::from_std(listener)))
+    }
+
+    // The peer's name for logs and the tui, and its address for the access list when it has one
+    fn accept(&self) -> io::Result<(Socket, String, Option<IpAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (sock, addr) = listener.accept()?;
+                Ok((Socket::Tcp(sock), addr.to_string(), Some(addr.ip())))
+            }
+            // file permissions are the access control here
+            Listener::Unix(listener) => {
+                let (sock, _) = listener.accept()?;
+                let path = listener.local_addr()?;
+                let peer = path.as_pathname().map(|path| path.display().to_string()).unwrap_or_else(|| "unix socket".to_string());
+                Ok((Socket::Unix(sock), peer, None))
+            }
+        }
+    }
+
+    fn register(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => registry.register(listener, LISTENER, Interest::READABLE),
+            Listener::Unix(listener) => registry.register(listener, LISTENER, Interest::READABLE),
+        }
+    }
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        match self {
+            Socket::Tcp(sock) => registry.register(sock, token, interest),
+            Socket::Unix(sock) => registry.register(sock, token, interest),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Socket::Tcp(sock) => registry.deregister(sock),
+            Socket::Unix(sock) => registry.deregister(sock),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(sock) => sock.read(buf),
+            Socket::Unix(sock) => sock.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(sock) => sock.write(buf),
+            Socket::Unix(sock) => sock.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(sock) => sock.flush(),
+            Socket::Unix(sock) => sock.flush(),
+        }
+    }
+}
+
+// The writing half handed to the tui. Every flushed frame is passed to the event loop, which
+// owns the socket, so a client that stops reading never blocks the screen.
+struct QueueWriter {
+    token: Token,
+    pending: Vec<u8>,
+    queue: Sender<(Token, Vec<u8>)>,
+    waker: Arc<Waker>,
+}
+
+impl Write for QueueWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.queue
+            .send((self.token, mem::take(&mut self.pending)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "server stopped"))?;
+        self.waker.wake()
+    }
+}
+
+// Sends to the tui, remembering when it has gone away
+struct Tui {
+    producer: IngestSender,
+    gone: Cell<bool>,
+}
+
+impl Tui {
+    fn send(&self, client: ClientId, message: ClientMessage) {
+        if self.producer.send((client, message)).is_err() {
+            self.gone.set(true);
+        }
+    }
+}
+
+struct Connection {
+    client: ClientId,
+    peer: String,
+    sock: Socket,
+    tls: Option<ServerConnection>,
+    decoder: FrameDecoder,
+    // plaintext waiting for the socket, TLS connections buffer inside the session instead
+    outgoing: Vec<u8>,
+    // the nonce sent, until the client has answered it
+    challenge: Option<AuthChallenge>,
+    // until it is handed to the tui along with the connection
+    writer: Option<EnvelopeWriter<Box<dyn Write + Send>>>,
+    accepted_at: Instant,
+    last_heard: Instant,
+}
+
+impl Connection {
+    fn handshaking(&self) -> bool {
+        self.challenge.is_some() || self.tls.as_ref().is_some_and(|tls| tls.is_handshaking())
+    }
+
+    fn opened(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    fn wants_write(&self) -> bool {
+        match &self.tls {
+            Some(tls) => tls.wants_write(),
+            None => !self.outgoing.is_empty(),
+        }
+    }
+
+    // Drains the socket through buf, returns true once the peer has hung up
+    fn read_available(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        loop {
+            let Some(tls) = &mut self.tls else {
+                match self.sock.read(buf) {
+                    Ok(0) => return Ok(true),
+                    Ok(n) => {
+                        self.decoder.push(&buf[..n]);
+                        self.last_heard = Instant::now();
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+                continue;
+            };
+
+            let closed = match tls.read_tls(&mut self.sock) {
+                Ok(n) => n == 0,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if let Err(e) = tls.process_new_packets() {
+                // let the peer know why, the session is dead either way
+                let _ = tls.write_tls(&mut self.sock);
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, e));
+            }
+            loop {
+                match tls.reader().read(buf) {
+                    Ok(0) => return Ok(true),
+                    Ok(n) => {
+                        self.decoder.push(&buf[..n]);
+                        self.last_heard = Instant::now();
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(true),
+                    Err(e) => return Err(e),
+                }
+            }
+            if closed {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn queue(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let queued = match &mut self.tls {
+            // takes what fits under the session's buffer limit
+            Some(tls) => tls.writer().write(bytes)?,
+            None if self.outgoing.len() + bytes.len() <= MAX_QUEUED_BYTES => {
+                self.outgoing.extend_from_slice(bytes);
+                bytes.len()
+            }
+            None => 0,
+        };
+        if queued < bytes.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "client stopped reading control messages"));
+        }
+        Ok(())
+    }
+
+    // Writes as much as the socket takes, the rest goes out on the next writable event
+    fn flush(&mut self) -> io::Result<()> {
+        loop {
+            let written = match &mut self.tls {
+                Some(tls) if tls.wants_write() => tls.write_tls(&mut self.sock),
+                None if !self.outgoing.is_empty() => self.sock.write(&self.outgoing),
+                _ => return Ok(()),
+            };
+            match written {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    if self.tls.is_none() {
+                        self.outgoing.drain(..n);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // The tui hears about a connection once its handshakes are done, a failed one never shows up
+    fn open_if_ready(&mut self, tui: &Tui) {
+        if self.opened() || self.handshaking() {
+            return;
+        }
+        let writer = self.writer.take().unwrap();
+        tui.send(self.client, ClientMessage::Connected(self.peer.clone(), ClientControl::with_writer(writer)));
+    }
+
+    fn on_envelope(&mut self, envelope: Envelope, policy: &ListenerPolicy, tui: &Tui) -> io::Result<()> {
+        if let (Some(challenge), Some(key)) = (&self.challenge, &policy.psk) {
+            auth::check_response(key, challenge, &envelope)?;
+            self.challenge = None;
+            self.open_if_ready(tui);
+            return Ok(());
+        }
+        if let Some(message) = to_message(envelope) {
+            tui.send(self.client, message);
+        }
+        Ok(())
+    }
+
+    // Handles whatever the socket is ready for, returns true once the connection is finished
+    fn service(&mut self, policy: &ListenerPolicy, tui: &Tui, buf: &mut [u8]) -> io::Result<bool> {
+        let closed = self.read_available(buf)?;
+        self.open_if_ready(tui);
+
+        loop {
+            let frame = match self.decoder.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                // oversized or garbled frames only spoil themselves, the stream is still usable
+                Err(e) => {
+                    log!("Dropping frame from {}: {}", self.peer, e);
+                    continue;
+                }
+            };
+            match Envelope::from_bytes(&frame) {
+                Ok(envelope) => self.on_envelope(envelope, policy, tui)?,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => log!("Dropping frame from {}: {}", self.peer, e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.flush()?;
+        Ok(closed)
+    }
+}
+
+struct Server {
+    registry: Registry,
+    listener: Listener,
+    policy: ListenerPolicy,
+    tui: Tui,
+    connections: HashMap<Token, Connection>,
+    next_client: ClientId,
+    queue: Sender<(Token, Vec<u8>)>,
+    waker: Arc<Waker>,
+    // shared by every connection, they are read one at a time
+    read_buf: Vec<u8>,
+    // accepting failed with peers maybe still queued, which the listener won't signal again
+    accept_pending: bool,
+}
+
+impl Server {
+    fn accept_all(&mut self) {
+        loop {
+            let (sock, peer, ip) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.accept_pending = false;
+                    return;
+                }
+                // e.g. out of file descriptors, tried again on the loop's next wake or timeout
+                Err(e) => {
+                    if !self.accept_pending {
+                        log!("Failed to accept: {}", e);
+                    }
+                    self.accept_pending = true;
+                    return;
+                }
+            };
+            if let Err(e) = self.admit(sock, peer.clone(), ip) {
+                log!("Rejected {}: {}", peer, e);
+            }
+        }
+    }
+
+    fn admit(&mut self, mut sock: Socket, peer: String, ip: Option<IpAddr>) -> io::Result<()> {
+        // turned away before anything is spent on them
+        if let Some(ip) = ip {
+            self.policy.access.check(ip).map_err(io::Error::other)?;
+        }
+        if self.policy.max_clients.is_some_and(|max| self.connections.len() >= max) {
+            return Err(io::Error::other(format!("already serving {} clients", self.connections.len())));
+        }
+
+        // each connection gets its own id so the tui can keep one App per client
+        let client = self.next_client;
+        self.next_client += 1;
+        let token = Token(FIRST_CONNECTION + client as usize);
+        let tls = match &self.policy.tls {
+            Some(config) => {
+                let mut session = ServerConnection::new(config.clone()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                session.set_buffer_limit(Some(MAX_QUEUED_BYTES));
+                Some(session)
+            }
+            None => None,
+        };
+        let queue_writer = QueueWriter { token, pending: Vec::new(), queue: self.queue.clone(), waker: Arc::clone(&self.waker) };
+        let mut writer = EnvelopeWriter::new(Box::new(queue_writer) as Box<dyn Write + Send>);
+        let challenge = match &self.policy.psk {
+            Some(key) => {
+                let challenge = key.challenge()?;
+                writer.send(MessageKind::AuthChallenge, &challenge)?;
+                Some(challenge)
+            }
+            None => None,
+        };
+
+        sock.register(&self.registry, token)?;
+        let now = Instant::now();
+        let mut connection = Connection {
+            client,
+            peer,
+            sock,
+            tls,
+            decoder: FrameDecoder::new(),
+            outgoing: Vec::new(),
+            challenge,
+            writer: Some(writer),
+            accepted_at: now,
+            last_heard: now,
+        };
+        connection.open_if_ready(&self.tui);
+        self.connections.insert(token, connection);
+        Ok(())
+    }
+
+    fn close(&mut self, token: Token, error: Option<io::Error>) {
+        let Some(mut connection) = self.connections.remove(&token) else { return };
+        let _ = connection.sock.deregister(&self.registry);
+        match error {
+            Some(e) if connection.opened() => log!("Lost {}: {}", connection.peer, e),
+            Some(e) => log!("Rejected {}: {}", connection.peer, e),
+            None if !connection.opened() => log!("Rejected {}: hung up during the handshake", connection.peer),
+            None => {}
+        }
+        if connection.opened() {
+            self.tui.send(connection.client, ClientMessage::Disconnected);
+        }
+    }
+
+    fn on_ready(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else { return };
+        match connection.service(&self.policy, &self.tui, &mut self.read_buf) {
+            Ok(false) => {}
+            Ok(true) => self.close(token, None),
+            Err(e) => self.close(token, Some(e)),
+        }
+    }
+
+    fn on_queued(&mut self, queued: &Receiver<(Token, Vec<u8>)>) {
+        while let Ok((token, bytes)) = queued.try_recv() {
+            // the client may be gone already
+            let Some(connection) = self.connections.get_mut(&token) else { continue };
+            if let Err(e) = connection.queue(&bytes).and_then(|()| connection.flush()) {
+                self.close(token, Some(e));
+            }
+        }
+    }
+
+    // Gives what is still queued, the tui's goodbyes included, a moment to go out before the
+    // sockets are closed
+    fn finish(&mut self, poll: &mut Poll, events: &mut Events, queued: &Receiver<(Token, Vec<u8>)>) {
+        self.on_queued(queued);
+        for connection in self.connections.values_mut() {
+            if let Some(tls) = &mut connection.tls {
+                tls.send_close_notify();
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(GOODBYE_FLUSH_MILLIS);
+        loop {
+            self.connections.retain(|_, connection| connection.flush().is_ok() && connection.wants_write());
+            let now = Instant::now();
+            if self.connections.is_empty() || now >= deadline {
+                return;
+            }
+            if let Err(e) = poll.poll(events, Some(deadline - now)) {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return;
+                }
+            }
+        }
+    }
+
+    // a client that vanished without closing the socket (e.g. Wi-Fi dropped) is noticed here
+    fn sweep(&mut self, now: Instant) {
+        let handshake_timeout = Duration::from_millis(HANDSHAKE_TIMEOUT_MILLIS);
+        let client_timeout = Duration::from_millis(CLIENT_TIMEOUT_MILLIS);
+        let expired: Vec<(Token, &'static str)> = self
+            .connections
+            .iter()
+            .filter_map(|(token, connection)| {
+                if !connection.opened() && now.duration_since(connection.accepted_at) > handshake_timeout {
+                    Some((*token, "handshake timed out"))
+                } else if connection.opened() && now.duration_since(connection.last_heard) > client_timeout {
+                    Some((*token, "silent for too long"))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for (token, reason) in expired {
+            self.close(token, Some(io::Error::new(io::ErrorKind::TimedOut, reason)));
+        }
+    }
+}
+
+// Serves every client of the listener until shutdown, or until the tui exits
+pub fn serve(mut listener: Listener, policy: ListenerPolicy, producer: IngestSender, shutdown: &Shutdown) -> io::Result<()> {
+    let mut poll = Poll::new()?;
+    listener.register(poll.registry())?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    shutdown.wake_on_request(Arc::clone(&waker));
+    let (queue, queued) = channel();
+    let mut server = Server {
+        registry: poll.registry().try_clone()?,
+        listener,
+        policy,
+        tui: Tui { producer, gone: Cell::new(false) },
+        connections: HashMap::new(),
+        next_client: 0,
+        queue,
+        waker,
+        read_buf: vec![0; READ_CHUNK_SIZE],
+        accept_pending: false,
+    };
+    let mut events = Events::with_capacity(1024);
+    let mut last_sweep = Instant::now();
+
+    while !server.tui.gone.get() && !shutdown.requested() {
+        match poll.poll(&mut events, Some(Duration::from_millis(SWEEP_PERIOD_MILLIS))) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        let mut accept = server.accept_pending;
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => accept = true,
+                // control messages, picked up below
+                WAKER => {}
+                token => server.on_ready(token),
+            }
+        }
+        if accept {
+            server.accept_all();
+        }
+        server.on_queued(&queued);
+
+        if last_sweep.elapsed() >= Duration::from_millis(SWEEP_PERIOD_MILLIS) {
+            last_sweep = Instant::now();
+            server.sweep(last_sweep);
+        }
+    }
+    server.finish(&mut poll, &mut events, &queued);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::AccessList;
+    use crate::ingest::{self, Backpressure, IngestReceiver};
+    use pitop_protocol::auth::PreSharedKey;
+    use pitop_protocol::tls::{self, TlsStream};
+    use pitop_protocol::{Control, EnvelopeReader};
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use util_bundle::UtilBundle;
+
+    fn start(policy: ListenerPolicy) -> (std::net::SocketAddr, IngestReceiver) {
+        let (addr, consumer, _) = start_with_shutdown(policy, Shutdown::new());
+        (addr, consumer)
+    }
+
+    fn start_with_shutdown(policy: ListenerPolicy, shutdown: Shutdown) -> (std::net::SocketAddr, IngestReceiver, thread::JoinHandle<()>) {
+        start_on(thread::Builder::new(), policy, shutdown)
+    }
+
+    fn start_on(thread: thread::Builder, policy: ListenerPolicy, shutdown: Shutdown) -> (std::net::SocketAddr, IngestReceiver, thread::JoinHandle<()>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (producer, consumer) = ingest::channel(64, Backpressure::DropNewest);
+        let server = thread.spawn(move || serve(Listener::tcp(listener).unwrap(), policy, producer, &shutdown).unwrap()).unwrap();
+        (addr, consumer, server)
+    }
+
+    // Whatever reaches the tui within a few seconds, stopping early once there are n messages
+    fn receive(consumer: &IngestReceiver, n: usize) -> Vec<(ClientId, ClientMessage)> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut received = Vec::new();
+        while received.len() < n && Instant::now() < deadline {
+            received.extend(consumer.drain(Duration::from_millis(100)));
+        }
+        received
+    }
+
+    fn open_policy() -> ListenerPolicy {
+        ListenerPolicy { tls: None, psk: None, access: AccessList::default(), max_clients: None }
+    }
+
+    // Threads spawned without a name take their parent's, so this counts a named thread and
+    // whatever it spawned, unlike the process-wide count other tests add to
+    fn threads_named(name: &str) -> usize {
+        std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter(|task| {
+                let comm = std::fs::read_to_string(task.as_ref().unwrap().path().join("comm")).unwrap_or_default();
+                comm.trim_end() == name
+            })
+            .count()
+    }
+
+    // Load test: hundreds of clients, each sending a few envelopes and reading a control message
+    // back, are all served without a thread apiece
+    #[test]
+    fn serves_hundreds_of_clients_from_one_thread() {
+        const CLIENTS: usize = 300;
+        const ROUNDS: usize = 5;
+        const SERVE_THREAD: &str = "load-test-loop";
+        let (addr, consumer, _) = start_on(thread::Builder::new().name(SERVE_THREAD.to_string()), open_policy(), Shutdown::new());
+
+        let mut clients: Vec<_> = (0..CLIENTS)
+            .map(|_| {
+                let sock = std::net::TcpStream::connect(addr).unwrap();
+                (EnvelopeWriter::new(sock.try_clone().unwrap()), EnvelopeReader::new(sock))
+            })
+            .collect();
+        for _ in 0..ROUNDS {
+            for (writer, _) in &mut clients {
+                writer.send(MessageKind::Sample, &UtilBundle::default()).unwrap();
+            }
+        }
+
+        let mut controls = Vec::new();
+        let mut samples = 0;
+        for (_, message) in receive(&consumer, CLIENTS * (ROUNDS + 1)) {
+            match message {
+                ClientMessage::Connected(_, control) => controls.push(control),
+                ClientMessage::Sample(_) => samples += 1,
+                _ => {}
+            }
+        }
+        assert_eq!(controls.len(), CLIENTS);
+        assert_eq!(samples, CLIENTS * ROUNDS);
+        assert_eq!(threads_named(SERVE_THREAD), 1);
+
+        for control in &controls {
+            control.send(&Control::SampleNow).unwrap();
+        }
+        for (_, reader) in &mut clients {
+            assert_eq!(reader.read_envelope().unwrap().unwrap().kind, MessageKind::Control);
+        }
+
+        drop(clients);
+        let disconnected = receive(&consumer, CLIENTS)
+            .iter()
+            .filter(|(_, message)| matches!(message, ClientMessage::Disconnected))
+            .count();
+        assert_eq!(disconnected, CLIENTS);
+    }
+
+    #[test]
+    fn only_clients_with_the_key_reach_the_tui() {
+        let (addr, consumer) = start(ListenerPolicy { psk: Some(PreSharedKey::new(b"hunter2")), ..open_policy() });
+
+        for key in [b"hunter3", b"hunter2"] {
+            let sock = std::net::TcpStream::connect(addr).unwrap();
+            let mut writer = EnvelopeWriter::new(sock.try_clone().unwrap());
+            let mut reader = EnvelopeReader::new(sock);
+            auth::answer_challenge(&mut reader, &mut writer, &PreSharedKey::new(key)).unwrap();
+            if key == b"hunter3" {
+                // hung up on
+                assert!(!matches!(reader.read_envelope(), Ok(Some(_))));
+            } else {
+                writer.send(MessageKind::Heartbeat, &()).unwrap();
+            }
+        }
+
+        let received = receive(&consumer, 2);
+        assert!(matches!(received[..], [(1, ClientMessage::Connected(..)), (1, ClientMessage::Heartbeat), ..]));
+    }
+
+    // made by testdata/generate.sh
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    // Connects as a client holding the named certificate, if any
+    fn dial_tls(addr: SocketAddr, cert: Option<&str>) -> io::Result<TlsStream> {
+        let cert = cert.map(|name| (testdata(&format!("{}.pem", name)), testdata(&format!("{}.key", name))));
+        let config = tls::client_config(&testdata("ca.pem"), cert.as_ref().map(|(cert, key)| (cert.as_path(), key.as_path())))?;
+        let sock = std::net::TcpStream::connect(addr)?;
+        sock.set_read_timeout(Some(Duration::from_secs(5)))?;
+        TlsStream::connect(config, "localhost", sock)
+    }
+
+    #[test]
+    fn only_clients_with_a_certificate_from_the_ca_reach_the_tui() {
+        let config = tls::server_config(&testdata("server.pem"), &testdata("server.key"), Some(&testdata("ca.pem"))).unwrap();
+        let (addr, consumer) = start(ListenerPolicy { tls: Some(config), ..open_policy() });
+
+        for cert in [None, Some("other_client")] {
+            // with TLS 1.3 the client finishes its side first and hears of the rejection on reading
+            if let Ok(mut stream) = dial_tls(addr, cert) {
+                assert!(stream.read(&mut [0u8; 1]).is_err());
+            }
+        }
+
+        let stream = dial_tls(addr, Some("client")).unwrap();
+        let mut writer = EnvelopeWriter::new(stream.try_clone().unwrap());
+        let mut reader = EnvelopeReader::new(stream);
+        // one clone blocks reading while the other writes, as the client's threads do
+        let answer = thread::spawn(move || reader.read_envelope().map(|envelope| envelope.map(|envelope| envelope.kind)));
+        writer.send(MessageKind::Heartbeat, &()).unwrap();
+
+        match &receive(&consumer, 2)[..] {
+            [(2, ClientMessage::Connected(_, control)), (2, ClientMessage::Heartbeat), ..] => control.send(&Control::SampleNow).unwrap(),
+            other => panic!("{} messages instead of the third client's connected and heartbeat", other.len()),
+        }
+        assert_eq!(answer.join().unwrap().unwrap(), Some(MessageKind::Control));
+    }
+
+    #[test]
+    fn shutdown_says_goodbye_before_closing() {
+        let shutdown = Shutdown::new();
+        let (addr, consumer, server) = start_with_shutdown(open_policy(), shutdown.clone());
+        let sock = std::net::TcpStream::connect(addr).unwrap();
+        let mut reader = EnvelopeReader::new(sock);
+
+        let Some((_, ClientMessage::Connected(_, control))) = receive(&consumer, 1).pop() else { panic!("never connected") };
+        control.goodbye("server shutting down").unwrap();
+        shutdown.request();
+        server.join().unwrap();
+
+        assert_eq!(reader.read_envelope().unwrap().unwrap().kind, MessageKind::Goodbye);
+        assert!(reader.read_envelope().unwrap().is_none());
+    }
+}
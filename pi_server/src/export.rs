@@ -0,0 +1,198 @@
+use crate::history::{History, Record};
+
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use util_bundle::{MetricGroup, UtilBundle};
+
+use std::io::{self, Write};
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+pub enum ExportFormat {
+    Csv,
+    // one JSON object per line, with the same keys as the CSV columns
+    Jsonl,
+}
+
+// e.g. 2026-10-18T09:30:00.250Z
+fn utc_time(at_ms: u64) -> String {
+    let secs = at_ms / 1000;
+    let (days, day_secs) = ((secs / 86_400) as i64, secs % 86_400);
+    // days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        day_secs / 3_600,
+        day_secs % 3_600 / 60,
+        day_secs % 60,
+        at_ms % 1000
+    )
+}
+
+fn columns(cores: usize) -> Vec<String> {
+    let mut columns: Vec<String> = ["host", "timestamp_ms", "time"].iter().map(|c| c.to_string()).collect();
+    columns.extend((0..cores).map(|core| format!("cpu{}_percent", core)));
+    columns.extend(
+        [
+            "cpu_temp_c",
+            "gpu_power_w",
+            "gpu_power_limit_w",
+            "mem_used_bytes",
+            "mem_total_bytes",
+            "disk_used_bytes",
+            "disk_total_bytes",
+            // since the previous sample
+            "net_tx_bytes",
+            "net_rx_bytes",
+        ]
+        .iter()
+        .map(|c| c.to_string()),
+    );
+    columns
+}
+
+// Clients measure in f32, written as such rather than as the f64 it widens to, 12.3 and not
+// 12.300000190734863
+fn f32_value(value: f32) -> Value {
+    json!(value.to_string().parse::<f64>().unwrap_or(f64::NAN))
+}
+
+// One value per column, null where the client wasn't collecting that group
+fn row(hostname: &str, cores: usize, bundle: &UtilBundle) -> Vec<Value> {
+    let when = |group: MetricGroup, value: Value| if bundle.is_enabled(group) { value } else { Value::Null };
+    let mut row = vec![json!(hostname), json!(bundle.sampled_at_ms), json!(utc_time(bundle.sampled_at_ms))];
+    row.extend((0..cores).map(|core| when(MetricGroup::Cpu, bundle.cpu_usage.get(core).map_or(Value::Null, |util| f32_value(*util)))));
+    row.extend([
+        when(MetricGroup::CpuTemp, f32_value(bundle.cpu_temp)),
+        when(MetricGroup::Gpu, json!(bundle.gpu_power)),
+        when(MetricGroup::Gpu, json!(bundle.gpu_power_limit)),
+        when(MetricGroup::Memory, json!(bundle.mem_used)),
+        when(MetricGroup::Memory, json!(bundle.mem_total)),
+        when(MetricGroup::Disk, json!(bundle.disk_used)),
+        when(MetricGroup::Disk, json!(bundle.disk_total)),
+        when(MetricGroup::Network, json!(bundle.data_tx)),
+        when(MetricGroup::Network, json!(bundle.data_rx)),
+    ]);
+    row
+}
+
+fn csv_field(value: &Value) -> String {
+    let field = match value {
+        Value::Null => return String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+fn write_row<W: Write>(out: &mut W, format: ExportFormat, columns: &[String], row: Vec<Value>) -> io::Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            let fields: Vec<String> = row.iter().map(csv_field).collect();
+            writeln!(out, "{}", fields.join(","))
+        }
+        // keys in column order, which a serde_json map would sort
+        ExportFormat::Jsonl => {
+            let fields: Vec<String> = columns.iter().zip(row).map(|(column, value)| format!("{}:{}", json!(column), value)).collect();
+            writeln!(out, "{{{}}}", fields.join(","))
+        }
+    }
+}
+
+// Writes every sample kept in the history of one host, or of all of them, host by host and oldest
+// first. There is a cpu column for every core of the biggest host. Returns how many hosts and
+// samples were written.
+pub fn export<W: Write>(history: &History, hostname: Option<&str>, format: ExportFormat, out: &mut W) -> io::Result<(usize, u64)> {
+    let hosts: Vec<_> = history.hosts()?.into_iter().filter(|stored| hostname.is_none_or(|name| stored.host.hostname == name)).collect();
+    if let (Some(name), true) = (hostname, hosts.is_empty()) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no history of {}", name)));
+    }
+
+    let cores = hosts.iter().map(|stored| stored.host.core_count).max().unwrap_or(0);
+    let columns = columns(cores);
+    if format == ExportFormat::Csv {
+        writeln!(out, "{}", columns.join(","))?;
+    }
+
+    let mut samples = 0;
+    for stored in &hosts {
+        let mut written = Ok(());
+        history.for_each_record(stored, |record| {
+            // gaps are simply missing rows
+            let Record::Sample(bundle) = record else { return };
+            if written.is_ok() {
+                written = write_row(out, format, &columns, row(&stored.host.hostname, cores, &bundle));
+                samples += 1;
+            }
+        })?;
+        written?;
+    }
+    out.flush()?;
+    Ok((hosts.len(), samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitop_protocol::now_millis;
+    use std::time::Duration;
+    use util_bundle::HostInfo;
+
+    #[test]
+    fn timestamps_are_utc() {
+        assert_eq!(utc_time(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(utc_time(951_782_400_250), "2000-02-29T00:00:00.250Z");
+        assert_eq!(utc_time(1_792_315_845_001), "2026-10-18T09:30:45.001Z");
+    }
+
+    #[test]
+    fn exports_per_core_columns_as_csv_and_jsonl() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = History::open(dir.path().to_path_buf(), Duration::from_secs(3_600)).unwrap();
+        let now = now_millis();
+        history.hello(&HostInfo { hostname: "pi, kitchen".to_string(), core_count: 2, ..HostInfo::default() }).unwrap();
+        let bundle = UtilBundle {
+            sampled_at_ms: now,
+            cpu_usage: vec![12.3, 50.0],
+            data_tx: 2048,
+            disabled_groups: vec![MetricGroup::Gpu],
+            ..Default::default()
+        };
+        history.append_sample("pi, kitchen", &bundle).unwrap();
+        history.append_gap("pi, kitchen", now + 1).unwrap();
+        history.flush().unwrap();
+
+        let mut csv = Vec::new();
+        assert_eq!(export(&history, None, ExportFormat::Csv, &mut csv).unwrap(), (1, 1));
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("host,timestamp_ms,time,cpu0_percent,cpu1_percent,cpu_temp_c,gpu_power_w,"));
+        assert!(lines[1].starts_with(&format!("\"pi, kitchen\",{},{},12.3,50.0,0.0,,,", now, utc_time(now))));
+        assert!(lines[1].ends_with(",2048,0"));
+
+        let mut jsonl = Vec::new();
+        export(&history, Some("pi, kitchen"), ExportFormat::Jsonl, &mut jsonl).unwrap();
+        let sample: Value = serde_json::from_slice(&jsonl).unwrap();
+        assert_eq!(sample["cpu1_percent"], json!(50.0));
+        assert_eq!(sample["gpu_power_w"], Value::Null);
+        assert_eq!(sample["net_tx_bytes"], json!(2048));
+        assert!(String::from_utf8(jsonl).unwrap().starts_with("{\"host\":\"pi, kitchen\",\"timestamp_ms\":"));
+
+        assert!(export(&history, Some("laptop"), ExportFormat::Csv, &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,391 @@
+use pitop_protocol::now_millis;
+use serde::{Deserialize, Serialize};
+use util_bundle::{HostInfo, UtilBundle};
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// one segment file per hour of samples, retention deletes whole segments
+const SEGMENT_MILLIS: u64 = 3_600_000;
+// at most this much is lost when the power goes
+const FLUSH_PERIOD: Duration = Duration::from_secs(1);
+const HOST_FILE: &str = "host.json";
+const SEGMENT_EXTENSION: &str = "jsonl";
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Record {
+    Sample(UtilBundle),
+    Gap(u64),
+}
+
+impl Record {
+    fn at_ms(&self) -> u64 {
+        match self {
+            Record::Sample(bundle) => bundle.sampled_at_ms,
+            Record::Gap(at_ms) => *at_ms,
+        }
+    }
+}
+
+// Written without taking the bundle from the caller, reads back as a Record
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RecordRef<'a> {
+    Sample(&'a UtilBundle),
+    Gap(u64),
+}
+
+// A host found in the history directory
+pub struct StoredHost {
+    pub host: HostInfo,
+    dir: PathBuf,
+}
+
+// Each host's samples on disk, so a restarted server picks its charts up where they left off.
+// A host gets a directory with its last hello and hourly segment files of records, one JSON
+// record per line. Segments are only ever appended to and synced, so a power cut can at worst
+// tear the last line, which loading skips. The disk is written from a thread of its own so a slow
+// SD card never holds up the tui.
+pub struct History {
+    dir: PathBuf,
+    retention_ms: u64,
+    // lines not handed to the writer yet, by host directory and segment
+    pending: BTreeMap<(String, u64), Vec<u8>>,
+    // hosts whose last record was a gap, a silent host gets one every tick
+    in_gap: HashSet<String>,
+    last_flush: Instant,
+    writer: Sender<Job>,
+}
+
+// For the writer thread, done in the order they were sent
+enum Job {
+    // the host's directory and its hello
+    Hello(PathBuf, Vec<u8>),
+    // with somewhere to report back to when the caller waits for it
+    Lines(BTreeMap<(String, u64), Vec<u8>>, Option<Sender<io::Result<()>>>),
+}
+
+// FNV-1a, unlike the std hasher it is sure to stay the same across Rust versions
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+// Hostnames come from the clients, keep them from escaping the history directory. Hosts whose
+// names only differ in what is replaced (e.g. "pi a" and "pi_a") are kept apart by a hash of the
+// whole name.
+fn host_dir_name(hostname: &str) -> String {
+    let name: String = hostname
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+        .collect();
+    let name = if name.is_empty() || name.starts_with('.') { format!("_{}", name) } else { name };
+    format!("{}-{:016x}", name, fnv1a(hostname.as_bytes()))
+}
+
+fn segment_start(path: &Path) -> Option<u64> {
+    if path.extension()? != SEGMENT_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+fn segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(start) = segment_start(&path) {
+            segments.push((start, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+// Written next to the old one and renamed over it, so there is always a whole hello to read
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_data()?;
+    fs::rename(tmp, path)
+}
+
+fn append_lines(path: &Path, lines: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+    // a line torn by a power cut would swallow the first one written after it
+    if file.metadata()?.len() > 0 {
+        let mut last = [0u8; 1];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last != *b"\n" {
+            file.write_all(b"\n")?;
+        }
+    }
+    file.write_all(lines)?;
+    file.sync_data()
+}
+
+fn write_lines(dir: &Path, lines: BTreeMap<(String, u64), Vec<u8>>) -> io::Result<()> {
+    for ((host, segment), lines) in lines {
+        let dir = dir.join(host);
+        fs::create_dir_all(&dir)?;
+        append_lines(&dir.join(format!("{}.{}", segment, SEGMENT_EXTENSION)), &lines)?;
+    }
+    Ok(())
+}
+
+fn expire(dir: &Path, cutoff: u64) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let dir = entry?.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        for (start, path) in segments(&dir)? {
+            if start + SEGMENT_MILLIS < cutoff {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Lines that failed to write are dropped rather than piling up on a full disk. Segments only fall
+// out of retention as the cutoff moves into another hour, so expired ones are looked for after the
+// first lines are written and then once an hour, even with nothing to write.
+fn write_behind(dir: PathBuf, retention_ms: u64, jobs: Receiver<Job>) {
+    let mut expired_hour = None;
+    loop {
+        let cutoff = now_millis().saturating_sub(retention_ms);
+        let next_hour = Duration::from_millis(SEGMENT_MILLIS - cutoff % SEGMENT_MILLIS);
+        let (written, done) = match jobs.recv_timeout(next_hour) {
+            Ok(Job::Hello(host_dir, contents)) => {
+                if let Err(e) = fs::create_dir_all(&host_dir).and_then(|()| write_atomically(&host_dir.join(HOST_FILE), &contents)) {
+                    log!("Failed to save history: {}", e);
+                }
+                continue;
+            }
+            Ok(Job::Lines(lines, done)) => (write_lines(&dir, lines), done),
+            Err(RecvTimeoutError::Timeout) => (Ok(()), None),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        let cutoff = now_millis().saturating_sub(retention_ms);
+        let written = written.and_then(|()| {
+            if expired_hour == Some(cutoff / SEGMENT_MILLIS) {
+                return Ok(());
+            }
+            expired_hour = Some(cutoff / SEGMENT_MILLIS);
+            expire(&dir, cutoff)
+        });
+        match (written, done) {
+            (written, Some(done)) => {
+                let _ = done.send(written);
+            }
+            (Err(e), None) => log!("Failed to save history: {}", e),
+            (Ok(()), None) => {}
+        }
+    }
+}
+
+impl History {
+    pub fn open(dir: PathBuf, retention: Duration) -> io::Result<History> {
+        fs::create_dir_all(&dir)?;
+        let retention_ms = retention.as_millis() as u64;
+        let (writer, jobs) = channel();
+        let writer_dir = dir.clone();
+        thread::spawn(move || write_behind(writer_dir, retention_ms, jobs));
+        Ok(History { dir, retention_ms, pending: BTreeMap::new(), in_gap: HashSet::new(), last_flush: Instant::now(), writer })
+    }
+
+    fn cutoff_ms(&self) -> u64 {
+        now_millis().saturating_sub(self.retention_ms)
+    }
+
+    // Hosts with a hello on disk, by hostname
+    pub fn hosts(&self) -> io::Result<Vec<StoredHost>> {
+        let mut hosts = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let dir = entry?.path();
+            let Ok(hello) = fs::read(dir.join(HOST_FILE)) else { continue };
+            let Ok(host) = serde_json::from_slice::<HostInfo>(&hello) else { continue };
+            hosts.push(StoredHost { host, dir });
+        }
+        hosts.sort_by(|a, b| a.host.hostname.cmp(&b.host.hostname));
+        Ok(hosts)
+    }
+
+    // One host's records within retention, a segment at a time so a week of them never has to
+    // fit in memory. Records come in time order within a segment, samples a client spooled through
+    // an outage arrive late.
+    pub fn for_each_record<F: FnMut(Record)>(&self, stored: &StoredHost, mut each: F) -> io::Result<()> {
+        let cutoff = self.cutoff_ms();
+        for (start, path) in segments(&stored.dir)? {
+            if start + SEGMENT_MILLIS < cutoff {
+                continue;
+            }
+            let mut records = Vec::new();
+            for line in BufReader::new(File::open(path)?).lines() {
+                match serde_json::from_str::<Record>(&line?) {
+                    Ok(record) if record.at_ms() >= cutoff => records.push(record),
+                    _ => {}
+                }
+            }
+            records.sort_by_key(Record::at_ms);
+            records.into_iter().for_each(&mut each);
+        }
+        Ok(())
+    }
+
+    pub fn hello(&mut self, host: &HostInfo) -> io::Result<()> {
+        self.send(Job::Hello(self.dir.join(host_dir_name(&host.hostname)), serde_json::to_vec(host)?))
+    }
+
+    // Both buffered until the next flush
+    pub fn append_sample(&mut self, hostname: &str, bundle: &UtilBundle) -> io::Result<()> {
+        let host = host_dir_name(hostname);
+        self.in_gap.remove(&host);
+        self.append(host, bundle.sampled_at_ms, &RecordRef::Sample(bundle))
+    }
+
+    pub fn append_gap(&mut self, hostname: &str, at_ms: u64) -> io::Result<()> {
+        let host = host_dir_name(hostname);
+        if !self.in_gap.insert(host.clone()) {
+            return Ok(());
+        }
+        self.append(host, at_ms, &RecordRef::Gap(at_ms))
+    }
+
+    fn append(&mut self, host: String, at_ms: u64, record: &RecordRef) -> io::Result<()> {
+        let segment = at_ms / SEGMENT_MILLIS * SEGMENT_MILLIS;
+        let lines = self.pending.entry((host, segment)).or_default();
+        serde_json::to_writer(&mut *lines, record)?;
+        lines.push(b'\n');
+        Ok(())
+    }
+
+    fn send(&self, job: Job) -> io::Result<()> {
+        self.writer.send(job).map_err(|_| io::Error::other("history writer is gone"))
+    }
+
+    // Hands what was buffered to the writer without waiting for it, which reports its own errors
+    pub fn flush_if_due(&mut self, now: Instant) -> io::Result<()> {
+        if self.pending.is_empty() || now.duration_since(self.last_flush) < FLUSH_PERIOD {
+            return Ok(());
+        }
+        self.last_flush = now;
+        let lines = std::mem::take(&mut self.pending);
+        self.send(Job::Lines(lines, None))
+    }
+
+    // Waits until everything buffered, and everything handed over before, is written and synced
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        let (done, written) = channel();
+        let lines = std::mem::take(&mut self.pending);
+        self.send(Job::Lines(lines, Some(done)))?;
+        written.recv().map_err(|_| io::Error::other("history writer is gone"))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(sampled_at_ms: u64, cpu: f32) -> UtilBundle {
+        UtilBundle { sampled_at_ms, cpu_usage: vec![cpu], ..Default::default() }
+    }
+
+    fn host(hostname: &str) -> HostInfo {
+        HostInfo { hostname: hostname.to_string(), ..HostInfo::default() }
+    }
+
+    // Each record's time and, for samples, the first core's load
+    fn records(history: &History, stored: &StoredHost) -> Vec<(u64, Option<f32>)> {
+        let mut records = Vec::new();
+        history
+            .for_each_record(stored, |record| {
+                records.push(match record {
+                    Record::Sample(bundle) => (bundle.sampled_at_ms, Some(bundle.cpu_usage[0])),
+                    Record::Gap(at_ms) => (at_ms, None),
+                })
+            })
+            .unwrap();
+        records
+    }
+
+    #[test]
+    fn flushed_records_survive_a_torn_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = History::open(dir.path().to_path_buf(), Duration::from_secs(3_600)).unwrap();
+        let now = now_millis();
+        history.hello(&host("workstation")).unwrap();
+        history.append_sample("workstation", &bundle(now - 2_000, 10.0)).unwrap();
+        history.append_gap("workstation", now - 1_000).unwrap();
+        history.append_gap("workstation", now - 500).unwrap();
+        // spooled during an outage, so older than what came before it
+        history.append_sample("workstation", &bundle(now - 3_000, 5.0)).unwrap();
+        history.flush().unwrap();
+
+        // the power went mid-write
+        let (_, segment) = segments(&dir.path().join(host_dir_name("workstation"))).unwrap().pop().unwrap();
+        OpenOptions::new().append(true).open(segment).unwrap().write_all(b"{\"sample\":{\"sampled_at").unwrap();
+        // and a restarted server carries on in the same segment
+        history.append_sample("workstation", &bundle(now - 100, 20.0)).unwrap();
+        history.flush().unwrap();
+
+        let hosts = history.hosts().unwrap();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].host, host("workstation"));
+        assert_eq!(
+            records(&history, &hosts[0]),
+            vec![(now - 3_000, Some(5.0)), (now - 2_000, Some(10.0)), (now - 1_000, None), (now - 100, Some(20.0))]
+        );
+    }
+
+    #[test]
+    fn old_segments_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = History::open(dir.path().to_path_buf(), Duration::from_secs(3 * 3_600)).unwrap();
+        let now = now_millis();
+        history.hello(&host("pi")).unwrap();
+        for hours_ago in 0..6 {
+            history.append_sample("pi", &bundle(now - hours_ago * SEGMENT_MILLIS, hours_ago as f32)).unwrap();
+        }
+        history.flush().unwrap();
+
+        let kept = segments(&dir.path().join(host_dir_name("pi"))).unwrap();
+        assert!(kept.len() == 3 || kept.len() == 4, "{:?}", kept);
+        assert_eq!(records(&history, &history.hosts().unwrap()[0]).len(), 3);
+    }
+
+    #[test]
+    fn hostnames_stay_inside_the_directory_and_apart() {
+        assert!(host_dir_name("workstation.lan").starts_with("workstation.lan-"));
+        assert!(host_dir_name("../etc").starts_with("_.._etc-"));
+        assert!(host_dir_name("a/b\\c").starts_with("a_b_c-"));
+        assert!(host_dir_name("").starts_with("_-"));
+        assert_ne!(host_dir_name("pi a"), host_dir_name("pi_a"));
+    }
+
+    #[test]
+    fn hosts_with_names_alike_keep_their_own_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = History::open(dir.path().to_path_buf(), Duration::from_secs(3_600)).unwrap();
+        let now = now_millis();
+        for (hostname, cpu) in [("pi a", 1.0), ("pi_a", 2.0)] {
+            history.hello(&host(hostname)).unwrap();
+            history.append_sample(hostname, &bundle(now, cpu)).unwrap();
+        }
+        history.flush().unwrap();
+
+        let hosts = history.hosts().unwrap();
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(records(&history, &hosts[0]), vec![(now, Some(1.0))]);
+        assert_eq!(records(&history, &hosts[1]), vec![(now, Some(2.0))]);
+    }
+}
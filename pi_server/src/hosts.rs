@@ -0,0 +1,409 @@
+use crate::app::App;
+use crate::history::{History, Record};
+use crate::session::{Playback, Recorder};
+use crate::{ClientId, ClientMessage};
+
+use pitop_protocol::now_millis;
+
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+// One App per monitored machine. Connections are mapped onto apps so that a client which
+// reconnects (same hostname in its hello) picks its old history back up instead of opening a new tab.
+pub struct Hosts {
+    pub apps: Vec<App>,
+    pub selected: usize,
+    // asked of every client as it connects, when configured
+    pub sample_interval_ms: Option<u64>,
+    // how the ingest queue is set up, for the status bar
+    pub queue: String,
+    // --record, every message is written to the session file
+    pub recorder: Option<Recorder>,
+    // --replay, messages come from a session file instead of clients
+    pub playback: Option<Playback>,
+    connections: HashMap<ClientId, usize>,
+    history: Option<History>,
+    stale_after: Duration,
+    gap_period: Duration,
+    last_gap: Instant,
+}
+
+impl Hosts {
+    // stale_after: silence after which a host is marked stale
+    // gap_period: how often a silent host gets a gap pushed into its charts
+    pub fn new(stale_after: Duration, gap_period: Duration) -> Hosts {
+        Hosts {
+            apps: Vec::new(),
+            selected: 0,
+            sample_interval_ms: None,
+            queue: String::new(),
+            recorder: None,
+            playback: None,
+            connections: HashMap::new(),
+            history: None,
+            stale_after,
+            gap_period,
+            last_gap: Instant::now(),
+        }
+    }
+
+    // Brings back the hosts seen before a restart, disconnected until they say hello again, and
+    // keeps saving from here on
+    pub fn restore(&mut self, history: History) -> io::Result<()> {
+        for stored in history.hosts()? {
+            let mut app = App::new();
+            history.for_each_record(&stored, |record| match record {
+                Record::Sample(bundle) => app.on_tick(bundle),
+                Record::Gap(at) => app.on_gap(at),
+            })?;
+            app.host = Some(stored.host);
+            self.apps.push(app);
+        }
+        self.history = Some(history);
+        Ok(())
+    }
+
+    // Hands what was saved since the last time to the history writer once it has been long enough,
+    // or on the way out writes everything and waits for it
+    pub fn save_history(&mut self, now: Option<Instant>) {
+        let Some(history) = &mut self.history else { return };
+        let saved = match now {
+            Some(now) => history.flush_if_due(now),
+            None => history.flush(),
+        };
+        if let Err(e) = saved {
+            log!("Failed to save history: {}", e);
+        }
+    }
+
+    pub fn on_message(&mut self, client: ClientId, message: ClientMessage) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(client, &message) {
+                log!("Failed to record session: {}", e);
+            }
+        }
+        let now = Instant::now();
+        if let Some(&idx) = self.connections.get(&client) {
+            self.apps[idx].on_seen(now);
+        }
+
+        match message {
+            ClientMessage::Connected(peer, control) => {
+                let mut app = App::new();
+                app.peer = peer;
+                app.connected = true;
+                app.control = Some(control);
+                if let Some(millis) = self.sample_interval_ms {
+                    app.set_sample_interval(millis);
+                }
+                self.apps.push(app);
+                self.connections.insert(client, self.apps.len() - 1);
+            }
+            ClientMessage::Hello(host) => {
+                let Some(&idx) = self.connections.get(&client) else { return };
+                let previous = self.apps.iter().position(|app| {
+                    !app.connected && app.host.as_ref().map(|h| &h.hostname) == Some(&host.hostname)
+                });
+
+                match previous {
+                    Some(previous) => {
+                        let peer = std::mem::take(&mut self.apps[idx].peer);
+                        let control = self.apps[idx].control.take();
+                        let dropped = self.apps[idx].dropped;
+                        let target = self.remove_app(idx, previous);
+                        self.connections.insert(client, target);
+                        let app = &mut self.apps[target];
+                        app.peer = peer;
+                        app.control = control;
+                        app.dropped += dropped;
+                        app.connected = true;
+                        app.left = false;
+                        app.host = Some(host);
+                        app.sync_controls();
+                    }
+                    None => self.apps[idx].host = Some(host),
+                }
+                let app = &self.apps[self.connections[&client]];
+                if let (Some(history), Some(host)) = (&mut self.history, &app.host) {
+                    if let Err(e) = history.hello(host) {
+                        log!("Failed to save history: {}", e);
+                    }
+                }
+            }
+            ClientMessage::Heartbeat => {}
+            ClientMessage::Link(stats) => {
+                if let Some(&idx) = self.connections.get(&client) {
+                    self.apps[idx].link = Some(stats);
+                }
+            }
+            ClientMessage::Dropped(count) => {
+                if let Some(&idx) = self.connections.get(&client) {
+                    self.apps[idx].dropped += count;
+                }
+            }
+            ClientMessage::Sample(mut datapoint) => {
+                if let Some(&idx) = self.connections.get(&client) {
+                    // stamped here so the saved copy charts at the same time after a restart
+                    if datapoint.sampled_at_ms == 0 {
+                        datapoint.sampled_at_ms = now_millis();
+                    }
+                    // kept from the hello on, before that there is no telling whose it is
+                    if let (Some(history), Some(host)) = (&mut self.history, &self.apps[idx].host) {
+                        if let Err(e) = history.append_sample(&host.hostname, &datapoint) {
+                            log!("Failed to save history: {}", e);
+                        }
+                    }
+                    self.apps[idx].on_tick(datapoint);
+                }
+            }
+            ClientMessage::Goodbye(_) => {
+                if let Some(&idx) = self.connections.get(&client) {
+                    self.apps[idx].left = true;
+                }
+            }
+            ClientMessage::Disconnected => {
+                if let Some(idx) = self.connections.remove(&client) {
+                    self.apps[idx].connected = false;
+                    self.apps[idx].control = None;
+                }
+            }
+            ClientMessage::Rewind(samples) => {
+                if let Some(&idx) = self.connections.get(&client) {
+                    self.apps[idx].clear_charts();
+                    samples.into_iter().for_each(|datapoint| self.apps[idx].on_tick(datapoint));
+                }
+            }
+        }
+    }
+
+    // Lets every connected client know the server is going away, so it doesn't count as an outage
+    pub fn say_goodbye(&self, reason: &str) {
+        for control in self.apps.iter().filter_map(|app| app.control.as_ref()) {
+            let _ = control.goodbye(reason);
+        }
+    }
+
+    // Marks hosts that went quiet as stale, and keeps the charts of stale and disconnected hosts
+    // scrolling with gaps so their last real samples don't look current
+    pub fn check_staleness(&mut self, now: Instant) {
+        for app in self.apps.iter_mut().filter(|app| app.connected) {
+            app.stale = now.duration_since(app.last_seen) > self.stale_after;
+        }
+
+        if now.duration_since(self.last_gap) < self.gap_period {
+            return;
+        }
+        self.last_gap = now;
+        let at = now_millis();
+        for app in self.apps.iter_mut().filter(|app| app.stale || !app.connected) {
+            app.on_gap(at);
+            if let (Some(history), Some(host)) = (&mut self.history, &app.host) {
+                if let Err(e) = history.append_gap(&host.hostname, at) {
+                    log!("Failed to save history: {}", e);
+                }
+            }
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.apps.iter().map(|app| app.dropped).sum()
+    }
+
+    pub fn selected_app(&mut self) -> Option<&mut App> {
+        self.apps.get_mut(self.selected)
+    }
+
+    pub fn select_next(&mut self) {
+        if !self.apps.is_empty() {
+            self.selected = (self.selected + 1) % self.apps.len();
+        }
+    }
+
+    pub fn select_previous(&mut self) {
+        if !self.apps.is_empty() {
+            self.selected = (self.selected + self.apps.len() - 1) % self.apps.len();
+        }
+    }
+
+    // up and down move a whole row in the overview grid
+    pub fn select_up(&mut self, columns: usize) {
+        if self.selected >= columns {
+            self.selected -= columns;
+        }
+    }
+
+    pub fn select_down(&mut self, columns: usize) {
+        if self.selected + columns < self.apps.len() {
+            self.selected += columns;
+        }
+    }
+
+    pub fn select(&mut self, idx: usize) {
+        if idx < self.apps.len() {
+            self.selected = idx;
+        }
+    }
+
+    // Removes apps[idx], fixing up every index that pointed past it. Returns where `keep` ended up.
+    fn remove_app(&mut self, idx: usize, keep: usize) -> usize {
+        self.apps.remove(idx);
+        let shift = |i: usize| if i > idx { i - 1 } else { i };
+        self.connections.values_mut().for_each(|i| *i = shift(*i));
+        if self.selected == idx {
+            self.selected = shift(keep);
+        } else {
+            self.selected = shift(self.selected);
+        }
+        self.selected = self.selected.min(self.apps.len().saturating_sub(1));
+        shift(keep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::series::Stat;
+    use crate::ClientControl;
+    use pitop_protocol::{Envelope, MessageKind};
+    use util_bundle::{HostInfo, UtilBundle};
+
+    fn connected(peer: &str) -> ClientMessage {
+        ClientMessage::Connected(peer.to_string(), ClientControl::new(Box::new(std::io::sink())))
+    }
+
+    fn hosts() -> Hosts {
+        Hosts::new(Duration::from_secs(2), Duration::from_millis(250))
+    }
+
+    fn hello(hostname: &str) -> ClientMessage {
+        ClientMessage::Hello(HostInfo { hostname: hostname.to_string(), ..HostInfo::default() })
+    }
+
+    fn sample(cpu: f32) -> ClientMessage {
+        let mut bundle = UtilBundle::new();
+        bundle.cpu_usage = vec![cpu];
+        ClientMessage::Sample(bundle)
+    }
+
+    #[test]
+    fn keeps_clients_apart() {
+        let mut hosts = hosts();
+        hosts.on_message(0, connected("10.0.0.1:5000"));
+        hosts.on_message(1, connected("10.0.0.2:5000"));
+        hosts.on_message(0, sample(10.0));
+        hosts.on_message(1, sample(90.0));
+        hosts.on_message(1, sample(80.0));
+
+        assert_eq!(hosts.apps.len(), 2);
+        assert_eq!(hosts.apps[0].cpu_util[0].values().count(), 1);
+        assert_eq!(hosts.apps[1].cpu_util[0].values().count(), 2);
+    }
+
+    #[test]
+    fn silent_host_goes_stale_and_gets_gaps() {
+        let mut hosts = hosts();
+        hosts.on_message(0, connected("10.0.0.1:5000"));
+        hosts.on_message(0, sample(50.0));
+
+        let later = Instant::now() + Duration::from_secs(3);
+        hosts.check_staleness(later);
+        let app = &hosts.apps[0];
+        assert!(app.stale);
+        assert_eq!(app.cpu_util[0].values().count(), 2);
+        assert!(app.cpu_util[0].values().last().unwrap().is_nan());
+        assert_eq!(app.latest_cpu_average(), 50.0);
+
+        hosts.on_message(0, ClientMessage::Heartbeat);
+        assert!(!hosts.apps[0].stale);
+    }
+
+    #[test]
+    fn samples_from_a_client_with_a_slow_clock_land_in_the_last_minute() {
+        let mut hosts = hosts();
+        hosts.on_message(0, connected("10.0.0.1:5000"));
+        let behind = now_millis() - 5 * 60 * 1000;
+        // a sample spooled half a minute before it was sent, then a live one
+        for (taken_before_sending, cpu) in [(30_000, 20.0), (0, 10.0)] {
+            let bundle = UtilBundle { sampled_at_ms: behind - taken_before_sending, cpu_usage: vec![cpu], ..Default::default() };
+            let mut envelope = Envelope::new(MessageKind::Sample, 0, &bundle).unwrap();
+            envelope.sent_at_ms = behind;
+            hosts.on_message(0, crate::to_message(envelope).unwrap());
+        }
+
+        let now = now_millis();
+        let window = hosts.apps[0].cpu_util[0].window(now as f64, 60.0, Stat::Avg);
+        let points: Vec<(i64, f64)> = window.iter().filter(|(_, cpu)| !cpu.is_nan()).map(|(x, cpu)| (x.round() as i64, *cpu)).collect();
+        assert_eq!(points, vec![(-30, 20.0), (0, 10.0)]);
+    }
+
+    #[test]
+    fn grid_navigation_stays_in_bounds() {
+        let mut hosts = hosts();
+        for client in 0..5 {
+            hosts.on_message(client, connected(&format!("10.0.0.{}:5000", client)));
+        }
+
+        // 3 columns: [0 1 2] [3 4]
+        hosts.select_down(3);
+        assert_eq!(hosts.selected, 3);
+        hosts.select_down(3);
+        assert_eq!(hosts.selected, 3);
+        hosts.select(2);
+        hosts.select_down(3);
+        assert_eq!(hosts.selected, 2);
+        hosts.select_up(3);
+        assert_eq!(hosts.selected, 2);
+    }
+
+    #[test]
+    fn reconnecting_host_reuses_its_history() {
+        let mut hosts = hosts();
+        hosts.on_message(0, connected("10.0.0.1:5000"));
+        hosts.on_message(0, hello("workstation"));
+        hosts.on_message(0, sample(10.0));
+        hosts.on_message(1, connected("10.0.0.2:5000"));
+        hosts.on_message(1, hello("laptop"));
+        hosts.on_message(0, ClientMessage::Disconnected);
+
+        hosts.on_message(2, connected("10.0.0.1:5001"));
+        hosts.select(2);
+        hosts.on_message(2, hello("workstation"));
+        hosts.on_message(2, sample(20.0));
+
+        assert_eq!(hosts.apps.len(), 2);
+        assert_eq!(hosts.selected, 0);
+        let workstation = &hosts.apps[0];
+        assert!(workstation.connected);
+        assert_eq!(workstation.peer, "10.0.0.1:5001");
+        assert_eq!(workstation.cpu_util[0].values().count(), 2);
+    }
+
+    #[test]
+    fn restarted_server_restores_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = || History::open(dir.path().to_path_buf(), Duration::from_secs(3_600)).unwrap();
+
+        let mut before = hosts();
+        before.restore(history()).unwrap();
+        before.on_message(0, connected("10.0.0.1:5000"));
+        before.on_message(0, hello("workstation"));
+        before.on_message(0, sample(10.0));
+        before.on_message(0, sample(20.0));
+        before.save_history(None);
+
+        let mut after = hosts();
+        after.restore(history()).unwrap();
+        assert_eq!(after.apps.len(), 1);
+        assert_eq!(after.apps[0].label(), "workstation");
+        assert!(!after.apps[0].connected);
+        assert_eq!(after.apps[0].cpu_util[0].latest(), Some(20.0));
+
+        after.on_message(1, connected("10.0.0.1:5001"));
+        after.on_message(1, hello("workstation"));
+        assert_eq!(after.apps.len(), 1);
+        assert_eq!(after.apps[0].cpu_util[0].values().count(), 2);
+        // written before the directory goes
+        after.save_history(None);
+    }
+}
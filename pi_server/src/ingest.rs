@@ -0,0 +1,241 @@
+use crate::{ClientId, ClientMessage};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+// What happens to a sample that arrives while its client's queue is full
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+pub enum Backpressure {
+    // make room by throwing away the oldest queued sample
+    DropOldest,
+    // keep the queue as it is and throw away the new sample
+    DropNewest,
+    // collapse the whole backlog into the new sample
+    Coalesce,
+}
+
+// The tui went away, nobody will read what is sent
+#[derive(Debug)]
+pub struct Closed;
+
+#[derive(Default)]
+struct ClientQueue {
+    messages: VecDeque<ClientMessage>,
+    samples: usize,
+    // thrown away since the tui last drained this queue
+    dropped: u64,
+    // the client's Disconnected is queued, the queue goes once it has been drained
+    finished: bool,
+}
+
+impl ClientQueue {
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty() && self.dropped == 0
+    }
+
+    // Removes up to n of the oldest queued samples, leaving everything else in order
+    fn drop_oldest_samples(&mut self, n: usize) {
+        let mut left = n;
+        self.messages.retain(|message| {
+            if left > 0 && matches!(message, ClientMessage::Sample(_)) {
+                left -= 1;
+                return false;
+            }
+            true
+        });
+        self.samples -= n - left;
+        self.dropped += (n - left) as u64;
+    }
+}
+
+struct State {
+    queues: BTreeMap<ClientId, ClientQueue>,
+    receiver_gone: bool,
+}
+
+struct Shared {
+    capacity: usize,
+    policy: Backpressure,
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+// Queue between the connections and the tui. Each client gets its own bound on queued samples,
+// so a stalled tui costs a fixed amount of memory and one chatty client can't crowd out the
+// others. Everything else a client sends is kept, minus repeats that would mean nothing new.
+pub fn channel(capacity: usize, policy: Backpressure) -> (IngestSender, IngestReceiver) {
+    let shared = Arc::new(Shared {
+        capacity: capacity.max(1),
+        policy,
+        state: Mutex::new(State { queues: BTreeMap::new(), receiver_gone: false }),
+        ready: Condvar::new(),
+    });
+    (IngestSender { shared: Arc::clone(&shared) }, IngestReceiver { shared })
+}
+
+#[derive(Clone)]
+pub struct IngestSender {
+    shared: Arc<Shared>,
+}
+
+impl IngestSender {
+    pub fn send(&self, (client, message): (ClientId, ClientMessage)) -> Result<(), Closed> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().map_err(|_| Closed)?;
+        if state.receiver_gone {
+            return Err(Closed);
+        }
+        let queue = state.queues.entry(client).or_default();
+
+        match message {
+            ClientMessage::Sample(_) if queue.samples >= shared.capacity => match shared.policy {
+                Backpressure::DropNewest => queue.dropped += 1,
+                Backpressure::DropOldest => {
+                    queue.drop_oldest_samples(1);
+                    queue.messages.push_back(message);
+                    queue.samples += 1;
+                }
+                Backpressure::Coalesce => {
+                    queue.drop_oldest_samples(queue.samples);
+                    queue.messages.push_back(message);
+                    queue.samples += 1;
+                }
+            },
+            ClientMessage::Sample(_) => {
+                queue.messages.push_back(message);
+                queue.samples += 1;
+            }
+            // one queued heartbeat says all there is to say
+            ClientMessage::Heartbeat if queue.messages.iter().any(|m| matches!(m, ClientMessage::Heartbeat)) => {}
+            // only the latest link stats matter
+            ClientMessage::Link(_) => {
+                queue.messages.retain(|m| !matches!(m, ClientMessage::Link(_)));
+                queue.messages.push_back(message);
+            }
+            ClientMessage::Disconnected => {
+                queue.finished = true;
+                queue.messages.push_back(message);
+            }
+            _ => queue.messages.push_back(message),
+        }
+        shared.ready.notify_one();
+        Ok(())
+    }
+}
+
+pub struct IngestReceiver {
+    shared: Arc<Shared>,
+}
+
+impl IngestReceiver {
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    pub fn policy(&self) -> Backpressure {
+        self.shared.policy
+    }
+
+    // Waits up to timeout for anything to arrive, then takes everything queued. A client's
+    // drop count since the last drain comes first in its batch, as a Dropped message.
+    pub fn drain(&self, timeout: Duration) -> Vec<(ClientId, ClientMessage)> {
+        let Ok(state) = self.shared.state.lock() else { return Vec::new() };
+        let Ok((mut state, _)) = self
+            .shared
+            .ready
+            .wait_timeout_while(state, timeout, |state| state.queues.values().all(ClientQueue::is_empty))
+        else {
+            return Vec::new();
+        };
+
+        let mut drained = Vec::new();
+        for (&client, queue) in state.queues.iter_mut() {
+            if queue.dropped > 0 {
+                drained.push((client, ClientMessage::Dropped(queue.dropped)));
+                queue.dropped = 0;
+            }
+            drained.extend(queue.messages.drain(..).map(|message| (client, message)));
+            queue.samples = 0;
+        }
+        state.queues.retain(|_, queue| !queue.finished);
+        drained
+    }
+}
+
+impl Drop for IngestReceiver {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.receiver_gone = true;
+            state.queues.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util_bundle::UtilBundle;
+
+    fn sample(cpu: f32) -> ClientMessage {
+        ClientMessage::Sample(UtilBundle { cpu_usage: vec![cpu], ..Default::default() })
+    }
+
+    // What the tui would see of client 0 after seven samples, as drop counts and sample cpu values
+    fn after_seven_samples(policy: Backpressure) -> (u64, Vec<f32>) {
+        let (sender, receiver) = channel(3, policy);
+        sender.send((0, ClientMessage::Heartbeat)).unwrap();
+        for cpu in 1..=7 {
+            sender.send((0, sample(cpu as f32))).unwrap();
+        }
+
+        let mut dropped = 0;
+        let mut kept = Vec::new();
+        for (_, message) in receiver.drain(Duration::ZERO) {
+            match message {
+                ClientMessage::Dropped(n) => dropped += n,
+                ClientMessage::Sample(bundle) => kept.push(bundle.cpu_usage[0]),
+                ClientMessage::Heartbeat => {}
+                _ => panic!("unexpected message"),
+            }
+        }
+        (dropped, kept)
+    }
+
+    #[test]
+    fn full_queue_follows_the_policy() {
+        assert_eq!(after_seven_samples(Backpressure::DropOldest), (4, vec![5.0, 6.0, 7.0]));
+        assert_eq!(after_seven_samples(Backpressure::DropNewest), (4, vec![1.0, 2.0, 3.0]));
+        // 4 collapses 1 2 3, 5 and 6 queue up behind it, then 7 collapses those
+        assert_eq!(after_seven_samples(Backpressure::Coalesce), (6, vec![7.0]));
+    }
+
+    #[test]
+    fn keeps_lifecycle_messages_and_skips_repeats() {
+        let (sender, receiver) = channel(1, Backpressure::Coalesce);
+        sender.send((7, ClientMessage::Heartbeat)).unwrap();
+        sender.send((7, ClientMessage::Heartbeat)).unwrap();
+        sender.send((7, sample(1.0))).unwrap();
+        sender.send((7, sample(2.0))).unwrap();
+        sender.send((7, ClientMessage::Disconnected)).unwrap();
+
+        let drained = receiver.drain(Duration::ZERO);
+        let kinds: Vec<&str> = drained
+            .iter()
+            .map(|(_, message)| match message {
+                ClientMessage::Dropped(_) => "dropped",
+                ClientMessage::Heartbeat => "heartbeat",
+                ClientMessage::Sample(_) => "sample",
+                ClientMessage::Disconnected => "disconnected",
+                _ => "other",
+            })
+            .collect();
+        assert_eq!(kinds, ["dropped", "heartbeat", "sample", "disconnected"]);
+        assert!(receiver.drain(Duration::ZERO).is_empty());
+
+        drop(receiver);
+        assert!(sender.send((7, ClientMessage::Heartbeat)).is_err());
+    }
+}
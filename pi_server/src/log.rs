@@ -0,0 +1,45 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// how long a message stays in the status bar
+const SHOWN_MILLIS: u64 = 15_000;
+
+// What the server's threads have to report. Written to stderr it would land in the middle of the
+// dashboard, so while the tui has the terminal the latest message goes to its status bar instead.
+struct Log {
+    tui: bool,
+    latest: Option<(Instant, String)>,
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log { tui: false, latest: None });
+
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::log::write(format!($($arg)*))
+    };
+}
+
+pub fn write(message: String) {
+    let mut log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+    if log.tui {
+        log.latest = Some((Instant::now(), message));
+    } else {
+        drop(log);
+        eprintln!("{}", message);
+    }
+}
+
+// Set while the tui has the terminal, messages before and after it still go to stderr
+pub fn to_status_bar(tui: bool) {
+    let mut log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+    log.tui = tui;
+    log.latest = None;
+}
+
+pub fn latest() -> Option<String> {
+    let log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+    match &log.latest {
+        Some((at, message)) if at.elapsed() < Duration::from_millis(SHOWN_MILLIS) => Some(message.clone()),
+        _ => None,
+    }
+}
@@ -1,21 +1,54 @@
+#[macro_use]
+mod log;
 mod ui;
 mod app;
 mod terminal;
+mod hosts;
+mod access;
+mod udp;
+mod serial;
+mod unix;
+mod event_loop;
+mod series;
+mod ingest;
+mod crash;
+mod shutdown;
+mod history;
+mod session;
+mod export;
 use crate::app::App;
 use crate::terminal::tui;
-use util_bundle::UtilBundle;use std::io;
-use clap::Parser;
+use crate::access::{AccessList, Cidr};
+use crate::udp::process_incoming_udp;
+use crate::serial::process_incoming_serial;
+use crate::event_loop::Listener;
+use crate::ingest::{Backpressure, IngestSender};
+use crate::shutdown::Shutdown;
+use crate::crash::CrashReport;
+use crate::history::History;
+use crate::hosts::Hosts;
+use crate::session::{Recorder, Session};
+use crate::export::ExportFormat;
+use util_bundle::{HostInfo, UtilBundle};use std::io;
+use clap::{Parser, ValueEnum};
 
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc::{channel, Sender};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 
-const MAX_MESSAGE_LEN: usize = 65536;
-const MAX_BUFFER_SIZE: usize = 1024;
+use pitop_protocol::auth::PreSharedKey;
+use pitop_protocol::tls::{self, ServerConfig};
+use pitop_protocol::datagram::LinkStats;
+use pitop_protocol::discovery::{Beacon, BeaconSender};
+use pitop_protocol::{now_millis, Control, Envelope, EnvelopeReader, EnvelopeWriter, Goodbye, MessageKind};
 
 const POLLING_PERIOD_MILLIS: u64 = 250;
+// clients heartbeat at least once a second, a connection silent for this long is dead
+pub const CLIENT_TIMEOUT_MILLIS: u64 = 30_000;
+const BEACON_PERIOD_MILLIS: u64 = 1_000;
 
 #[derive(Parser)]
 #[command(name = "PiTop Pi Server")]
@@ -27,82 +60,372 @@ struct Args {
     ip: String,
     
     #[arg(short, long, default_value = "7878")]
-    port: String
+    port: String,
+
+    #[arg(long, value_enum, default_value = "tcp")]
+    transport: Transport,
+
+    /// Read a single client's frames from stdin instead of listening, e.g. `ssh host win_client --stdout | pi_server --stdin`
+    #[arg(long, conflicts_with_all = ["tls_cert", "psk_file", "beacon"])]
+    stdin: bool,
+
+    /// Read a single client over this serial device (e.g. /dev/ttyGS0) instead of listening
+    #[arg(long, conflicts_with_all = ["stdin", "transport", "tls_cert", "psk_file", "beacon"])]
+    serial: Option<PathBuf>,
+
+    /// Baud rate for --serial
+    #[arg(long, default_value = "115200", requires = "serial")]
+    baud: u32,
+
+    /// Listen on this Unix domain socket instead of a TCP port, for watching the local machine
+    #[arg(long, conflicts_with_all = ["stdin", "serial", "transport", "tls_cert", "allow", "deny", "beacon"])]
+    unix: Option<PathBuf>,
+
+    /// Permissions for the --unix socket (octal), whoever may write to it may connect
+    #[arg(long, default_value = "660", value_parser = unix::parse_mode, requires = "unix")]
+    unix_mode: u32,
+
+    /// Mark a client stale after this long without hearing from it
+    #[arg(long, default_value = "2000")]
+    stale_after_ms: u64,
+
+    /// Ask every client to sample at this interval when it connects
+    #[arg(long)]
+    sample_interval_ms: Option<u64>,
+
+    /// Samples queued per client while the display falls behind
+    #[arg(long, default_value = "64", value_parser = clap::value_parser!(u64).range(1..))]
+    queue_capacity: u64,
+
+    /// What to do with samples that arrive while a client's queue is full
+    #[arg(long, value_enum, default_value = "drop-oldest")]
+    backpressure: Backpressure,
+
+    /// Serve TLS with this certificate chain (PEM)
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Require client certificates signed by this CA (PEM), i.e. mutual TLS
+    #[arg(long, requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
+
+    /// Require clients to prove they hold the key in this file
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+
+    /// Only accept clients from this address or CIDR block (repeatable)
+    #[arg(long)]
+    allow: Vec<Cidr>,
+
+    /// Refuse clients from this address or CIDR block (repeatable, wins over --allow)
+    #[arg(long)]
+    deny: Vec<Cidr>,
+
+    /// Refuse new connections while this many clients are connected
+    #[arg(long)]
+    max_clients: Option<usize>,
+
+    /// Announce this server on the LAN so clients started without --ip can find it
+    #[arg(long)]
+    beacon: bool,
+
+    /// Where beacons go, a broadcast address or a multicast group such as 239.255.70.84:7879
+    #[arg(long, default_value = "255.255.255.255:7879")]
+    beacon_to: SocketAddr,
+
+    /// Name announced in beacons, defaults to the hostname
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Where a panic writes its report and the last bundles received, defaults to pi_server-crash.log in the temp dir
+    #[arg(long)]
+    crash_file: Option<PathBuf>,
+
+    /// Keep every host's samples in this directory and reload them on restart
+    #[arg(long)]
+    history_dir: Option<PathBuf>,
+
+    /// Hours of samples kept in --history-dir, and exported by --export
+    #[arg(long, default_value = "24", value_parser = clap::value_parser!(u64).range(1..), requires = "history_dir")]
+    history_hours: u64,
+
+    /// Write every message received to this new session file, for --replay
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Play a session file from --record back instead of listening
+    #[arg(long, conflicts_with_all = ["stdin", "serial", "unix", "tls_cert", "psk_file", "beacon", "record", "history_dir"])]
+    replay: Option<PathBuf>,
+
+    /// Write the samples in --history-dir to this file (- for stdout) and exit
+    #[arg(long, requires = "history_dir", conflicts_with_all = ["stdin", "serial", "unix", "beacon", "record", "replay"])]
+    export: Option<PathBuf>,
+
+    /// Only export this host, by hostname
+    #[arg(long, requires = "export")]
+    export_host: Option<String>,
+
+    /// File format for --export
+    #[arg(long, value_enum, default_value = "csv", requires = "export")]
+    export_format: ExportFormat,
 }
 
-fn handle_sender(mut in_stream: TcpStream, out_stream: Sender<UtilBundle>) -> io::Result<()> {
-    let mut received_data: Vec<u8> = Vec::new();
-    let mut buf = [0; MAX_BUFFER_SIZE];
-    // TODO: Use cntrl-c crate for graceful exit?
-    loop {
-        let bytes_read = in_stream.read(&mut buf)?;
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Debug)]
+enum Transport {
+    Tcp,
+    // one datagram per message, see pitop_protocol::datagram
+    Udp,
+}
 
-        if bytes_read == 0 {
-            return Ok(());
-        } else if bytes_read > MAX_MESSAGE_LEN {
-            return Err(io::Error::new(io::ErrorKind::Other, "Message too long"));
-        }
+// Identifies one accepted connection for as long as the server runs
+pub type ClientId = u64;
 
-        received_data.extend_from_slice(&buf[..bytes_read]);
-        // TODO: optimize search here to only search once for '\n' (other case is when we call .position())
-        if !buf.contains(&b'\n') {
-            // we haven't received the full message yet
-            // println!("haven't received full message yet");
-            continue;
-        } else {
-            // println!("received full message");
-        }
+// Lets the tui push control messages back down a client's connection
+#[derive(Clone)]
+pub struct ClientControl {
+    writer: Arc<Mutex<EnvelopeWriter<Box<dyn Write + Send>>>>,
+}
 
-        // TODO: Use debug levels & use a logging crate
-        // in_stream.write(&received_data)?;
-        // println!(
-        //     "from the sender: {}",
-        //     String::from_utf8_lossy(&received_data)
-        // );
-        let util_datapoint: UtilBundle = serde_json::from_slice(
-            &received_data[..received_data.iter().position(|&x| x == b'\n').unwrap_or(0)],
-        )?;
-        // println!("util_datapoint: {:?}", util_datapoint);
-        out_stream.send(util_datapoint);
-
-        // reduce overhead of looking for more client data
-        thread::sleep(time::Duration::from_millis(POLLING_PERIOD_MILLIS));
-        received_data.clear();
+impl ClientControl {
+    pub fn new(writer: Box<dyn Write + Send>) -> ClientControl {
+        ClientControl::with_writer(EnvelopeWriter::new(writer))
     }
 
-    // TODO: clearly define when we are done with a sender?
-    println!("exiting handle_sender");
-    Ok(())
+    // keeps numbering after whatever the handshake already sent
+    pub fn with_writer(writer: EnvelopeWriter<Box<dyn Write + Send>>) -> ClientControl {
+        ClientControl { writer: Arc::new(Mutex::new(writer)) }
+    }
+
+    pub fn send(&self, control: &Control) -> io::Result<()> {
+        let mut writer = self.writer.lock().map_err(|_| io::Error::other("control writer poisoned"))?;
+        writer.send(MessageKind::Control, control)
+    }
+
+    pub fn goodbye(&self, reason: &str) -> io::Result<()> {
+        let mut writer = self.writer.lock().map_err(|_| io::Error::other("control writer poisoned"))?;
+        writer.send(MessageKind::Goodbye, &Goodbye { reason: reason.to_string() })
+    }
+}
+
+// Everything a connection forwards to the tui
+pub enum ClientMessage {
+    Connected(String, ClientControl),
+    Hello(HostInfo),
+    Sample(UtilBundle),
+    Heartbeat,
+    // sequence tracking for datagram clients
+    Link(LinkStats),
+    // samples the ingest queue threw away since the last report
+    Dropped(u64),
+    // the client is about to hang up on purpose, Disconnected follows
+    Goodbye(String),
+    Disconnected,
+    // replays only, after a seek: the samples leading up to the new position, instead of the charts
+    Rewind(Vec<UtilBundle>),
+}
+
+// Who may connect and how they have to prove themselves
+struct ListenerPolicy {
+    tls: Option<Arc<ServerConfig>>,
+    psk: Option<PreSharedKey>,
+    access: AccessList,
+    max_clients: Option<usize>,
 }
 
-fn process_incoming_threaded(receiver_listener: TcpListener, utilbundle_producer: Sender<UtilBundle>) {
-    let mut thread_vec: Vec<thread::JoinHandle<()>> = Vec::new();
-    for stream in receiver_listener.incoming() {
-        let stream = stream.expect("Failed to get stream from receiver_listener");
-        let producer = utilbundle_producer.clone();
-        // let receiver connect with sender
-        // Might have to use Arc/Mutex here?
-        let handle = thread::spawn(move || {
-            handle_sender(stream, producer).unwrap_or_else(|error| eprintln!("{:?}", error))
-        });
-
-        thread_vec.push(handle);
+// What an envelope from a client means to the tui, None for messages it has no use for
+pub fn to_message(envelope: Envelope) -> Option<ClientMessage> {
+    // TODO: Use debug levels & use a logging crate
+    match envelope.kind {
+        MessageKind::Hello => match envelope.payload() {
+            Ok(host) => Some(ClientMessage::Hello(host)),
+            Err(e) => {
+                log!("Dropping malformed hello: {}", e);
+                None
+            }
+        },
+        MessageKind::Heartbeat => Some(ClientMessage::Heartbeat),
+        MessageKind::Goodbye => {
+            let reason = envelope.payload::<Goodbye>().map(|goodbye| goodbye.reason).unwrap_or_default();
+            Some(ClientMessage::Goodbye(reason))
+        }
+        // only ever sent by the server, or only valid during the handshake
+        MessageKind::Control | MessageKind::AuthChallenge | MessageKind::AuthResponse | MessageKind::Beacon => None,
+        MessageKind::Sample => {
+            let mut util_datapoint: UtilBundle = match envelope.payload() {
+                Ok(bundle) => bundle,
+                Err(e) => {
+                    log!("Dropping malformed bundle: {}", e);
+                    return None;
+                }
+            };
+            // The client's clock may be off from ours. Only how long before sending it was taken
+            // is kept, so live samples land at arrival and spooled ones that much earlier.
+            util_datapoint.sampled_at_ms = match (util_datapoint.sampled_at_ms, envelope.sent_at_ms) {
+                (0, _) | (_, 0) => 0,
+                (sampled_at_ms, sent_at_ms) => now_millis().saturating_sub(sent_at_ms.saturating_sub(sampled_at_ms)),
+            };
+            // println!("util_datapoint: {:?}", util_datapoint);
+            Some(ClientMessage::Sample(util_datapoint))
+        }
+        // newer client, message we don't understand yet
+        MessageKind::Unknown => None,
     }
+}
 
-    for handle in thread_vec {
-        handle.join().unwrap();
+fn handle_sender<R: Read>(client: ClientId, peer: String, mut envelopes: EnvelopeReader<R>, control: ClientControl, out_stream: IngestSender) -> io::Result<()> {
+    if out_stream.send((client, ClientMessage::Connected(peer, control))).is_err() {
+        return Ok(());
     }
+
+    loop {
+        let envelope = match envelopes.read_envelope() {
+            Ok(Some(envelope)) => envelope,
+            // client hung up between frames
+            Ok(None) => return Ok(()),
+            // oversized or garbled frames only spoil themselves, the stream is still usable
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                log!("Dropping frame: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let Some(message) = to_message(envelope) else { continue };
+
+        if out_stream.send((client, message)).is_err() {
+            // tui has exited, nobody is left to consume bundles
+            return Ok(());
+        }
+    }
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "pi_server".to_string())
+}
+
+fn spawn_beacon(target: SocketAddr, beacon: Beacon) -> io::Result<()> {
+    let mut sender = BeaconSender::new(target)?;
+    thread::spawn(move || loop {
+        if let Err(e) = sender.send(&beacon) {
+            log!("Failed to send beacon to {}: {}", target, e);
+        }
+        thread::sleep(time::Duration::from_millis(BEACON_PERIOD_MILLIS));
+    });
+    Ok(())
 }
 
 fn main() -> io::Result<()> {
     let args = Args::parse();
-    let tcp_listener = TcpListener::bind(format!("{}:{}", args.ip, args.port)).expect("Failed bind with sender");
+    let history_retention = time::Duration::from_secs(args.history_hours * 3_600);
+
+    // a one-off command, nothing is listened to or drawn
+    if let (Some(path), Some(dir)) = (&args.export, &args.history_dir) {
+        let history = History::open(dir.clone(), history_retention)?;
+        let host = args.export_host.as_deref();
+        let (hosts, samples) = if path.as_os_str() == "-" {
+            export::export(&history, host, args.export_format, &mut io::stdout().lock())?
+        } else {
+            export::export(&history, host, args.export_format, &mut io::BufWriter::new(std::fs::File::create(path)?))?
+        };
+        eprintln!("Exported {} samples of {} hosts", samples, hosts);
+        return Ok(());
+    }
+
+    let crash = CrashReport::new(args.crash_file.clone().unwrap_or_else(|| std::env::temp_dir().join("pi_server-crash.log")));
+    crash.install_panic_hook();
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(cert, key, args.tls_ca.as_deref())?),
+        _ => None,
+    };
+    let policy = ListenerPolicy {
+        tls,
+        psk: args.psk_file.as_deref().map(PreSharedKey::load).transpose()?,
+        access: AccessList { allow: args.allow, deny: args.deny },
+        max_clients: args.max_clients,
+    };
+    if args.transport == Transport::Udp && (policy.tls.is_some() || policy.psk.is_some()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "TLS and pre-shared keys need --transport tcp"));
+    }
+    let bind_addr = format!("{}:{}", args.ip, args.port);
+    if args.beacon {
+        let beacon = Beacon {
+            name: args.name.clone().unwrap_or_else(hostname),
+            port: args.port.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "--beacon needs a numeric --port"))?,
+            transport: args.transport.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default(),
+            tls: policy.tls.is_some(),
+            psk: policy.psk.is_some(),
+        };
+        spawn_beacon(args.beacon_to, beacon)?;
+    }
+
+    // bound before the tui takes over the terminal, so a taken path is reported readably
+    let unix_listener = args.unix.as_deref().map(|path| unix::bind(path, args.unix_mode)).transpose()?;
 
-    let (utilbundle_producer, utilbundle_consumer) = channel();
-    let tui_handler = thread::spawn(move || tui(utilbundle_consumer));
+    let shutdown = Shutdown::new();
+    shutdown.on_signals()?;
 
-    process_incoming_threaded(tcp_listener, utilbundle_producer);
-    tui_handler.join().unwrap();
+    let (utilbundle_producer, utilbundle_consumer) = ingest::channel(args.queue_capacity as usize, args.backpressure);
+    let mut hosts = Hosts::new(time::Duration::from_millis(args.stale_after_ms), time::Duration::from_millis(POLLING_PERIOD_MILLIS));
+    hosts.sample_interval_ms = args.sample_interval_ms;
+    let policy_name = utilbundle_consumer.policy().to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+    hosts.queue = format!("{}, {} samples per client", policy_name, utilbundle_consumer.capacity());
+    hosts.recorder = args.record.as_deref().map(Recorder::create).transpose()?;
+    // a day of samples takes a moment, better before the screen goes blank
+    if let Some(dir) = args.history_dir.clone() {
+        hosts.restore(History::open(dir, history_retention)?)?;
+    }
+    // loaded up front so a bad file is reported before the tui takes over the terminal
+    let session = args.replay.as_deref().map(Session::load).transpose()?;
+    let replayer = session.map(|session| {
+        let (playback, handle) = session::replay(session, utilbundle_producer.clone(), &shutdown);
+        hosts.playback = Some(playback);
+        handle
+    });
+
+    let tui_shutdown = shutdown.clone();
+    let tui_handler = thread::spawn(move || tui(utilbundle_consumer, hosts, tui_shutdown, crash));
+
+    // Blocking reads on stdin or a serial line can't be interrupted, those threads are simply
+    // left to go down with the process once the tui is done
+    match (&args.serial, unix_listener, args.transport) {
+        // the replay thread stands in for every client, it stops once the tui is done
+        _ if replayer.is_some() => {}
+        _ if args.stdin => {
+            thread::spawn(move || {
+                // one-way pipe, control messages have nowhere to go
+                let control = ClientControl::new(Box::new(io::sink()));
+                handle_sender(0, "stdin".to_string(), EnvelopeReader::new(io::stdin()), control, utilbundle_producer.clone())
+                    .unwrap_or_else(|error| log!("{:?}", error));
+                let _ = utilbundle_producer.send((0, ClientMessage::Disconnected));
+            });
+        }
+        (Some(path), _, _) => {
+            let (path, baud) = (path.clone(), args.baud);
+            thread::spawn(move || process_incoming_serial(&path, baud, utilbundle_producer));
+        }
+        (None, Some(unix_listener), _) => event_loop::serve(Listener::unix(unix_listener)?, policy, utilbundle_producer, &shutdown)?,
+        (None, None, Transport::Tcp) => {
+            let tcp_listener = TcpListener::bind(bind_addr).expect("Failed bind with sender");
+            event_loop::serve(Listener::tcp(tcp_listener)?, policy, utilbundle_producer, &shutdown)?;
+        }
+        (None, None, Transport::Udp) => {
+            let socket = UdpSocket::bind(bind_addr).expect("Failed bind with sender");
+            process_incoming_udp(socket, &policy.access, policy.max_clients, utilbundle_producer, &shutdown)?;
+        }
+    }
+    // the panic hook already wrote the crash report, a second panic here would overwrite it
+    tui_handler.join().map_err(|_| io::Error::other("the tui panicked"))??;
+    if let Some(replayer) = replayer {
+        let _ = replayer.join();
+    }
 
     Ok(())
 }
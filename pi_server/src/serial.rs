@@ -0,0 +1,32 @@
+use crate::ingest::IngestSender;
+use crate::{handle_sender, ClientControl, ClientMessage};
+
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use pitop_protocol::serial::SerialPort;
+use pitop_protocol::EnvelopeReader;
+
+// a USB gadget disappears while the cable is out, try again at this pace
+const REOPEN_PERIOD_MILLIS: u64 = 1_000;
+
+// One client on the other end of a serial line. The line never closes on its own, a client that
+// stops sending just goes stale. Only losing the device ends the session, then it is reopened
+// and whoever talks next shows up as a new client.
+pub fn process_incoming_serial(path: &Path, baud: u32, producer: IngestSender) {
+    let peer = path.display().to_string();
+    for client in 0.. {
+        let session = SerialPort::open(path, baud).and_then(|port| {
+            let control = ClientControl::new(Box::new(port.try_clone()?));
+            handle_sender(client, peer.clone(), EnvelopeReader::resyncing(port), control, producer.clone())
+        });
+        if let Err(error) = session {
+            log!("Serial line {}: {}", peer, error);
+        }
+        if producer.send((client, ClientMessage::Disconnected)).is_err() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(REOPEN_PERIOD_MILLIS));
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+
+// Older history is kept as min/avg/max buckets: 10 second buckets for the last hour and 5 minute
+// buckets for the last day
+const ROLLUPS: [(u64, usize); 2] = [(10_000, 360), (300_000, 288)];
+
+// What a point stands for once samples have been rolled up into buckets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stat {
+    Min,
+    Avg,
+    Max,
+}
+
+struct Bucket {
+    start_ms: f64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u32,
+}
+
+impl Bucket {
+    fn stat(&self, stat: Stat) -> f64 {
+        match stat {
+            Stat::Min => self.min,
+            Stat::Avg => self.sum / self.count as f64,
+            Stat::Max => self.max,
+        }
+    }
+}
+
+struct Rollup {
+    bucket_ms: f64,
+    buckets: VecDeque<Bucket>,
+    capacity: usize,
+}
+
+impl Rollup {
+    fn new(bucket_ms: u64, capacity: usize) -> Rollup {
+        Rollup { bucket_ms: bucket_ms as f64, buckets: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    fn span_ms(&self) -> f64 {
+        self.bucket_ms * self.capacity as f64
+    }
+
+    fn add(&mut self, at_ms: f64, value: f64) {
+        if let Some(bucket) = self.buckets.back_mut().filter(|bucket| at_ms < bucket.start_ms + self.bucket_ms) {
+            bucket.min = bucket.min.min(value);
+            bucket.max = bucket.max.max(value);
+            bucket.sum += value;
+            bucket.count += 1;
+            return;
+        }
+        if self.buckets.len() == self.capacity {
+            self.buckets.pop_front();
+        }
+        let start_ms = (at_ms / self.bucket_ms).floor() * self.bucket_ms;
+        self.buckets.push_back(Bucket { start_ms, min: value, max: value, sum: value, count: 1 });
+    }
+}
+
+// Timestamped samples of one metric, oldest first. Once full, each push drops the oldest point,
+// so a push costs the same however long the history is and nothing is ever renumbered.
+// Timestamps are wall clock milliseconds since the unix epoch, a NaN value marks a gap.
+pub struct Series {
+    points: VecDeque<(f64, f64)>,
+    capacity: usize,
+    rollups: Vec<Rollup>,
+}
+
+impl Series {
+    // capacity: raw points, older ones only survive in the rollups
+    pub fn new(capacity: usize) -> Series {
+        Series {
+            points: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            rollups: ROLLUPS.iter().map(|&(bucket_ms, buckets)| Rollup::new(bucket_ms, buckets)).collect(),
+        }
+    }
+
+    // Client and server clocks disagree a little, a point never lands before the one it follows
+    pub fn push(&mut self, at_ms: u64, value: f64) {
+        let at_ms = self.latest_at().map_or(at_ms as f64, |latest| latest.max(at_ms as f64));
+        if self.points.len() == self.capacity {
+            self.points.pop_front();
+        }
+        self.points.push_back((at_ms, value));
+        // a gap is just a missing bucket
+        if value.is_finite() {
+            self.rollups.iter_mut().for_each(|rollup| rollup.add(at_ms, value));
+        }
+    }
+
+    // One gap says the data stopped, repeating it would only push real samples out
+    pub fn push_gap(&mut self, at_ms: u64) {
+        if self.points.back().is_none_or(|(_, value)| value.is_finite()) {
+            self.push(at_ms, f64::NAN);
+        }
+    }
+
+    pub fn latest_at(&self) -> Option<f64> {
+        self.points.back().map(|(at, _)| *at)
+    }
+
+    // Latest real value, skipping gaps
+    pub fn latest(&self) -> Option<f64> {
+        self.points.iter().rev().map(|(_, value)| *value).find(|value| value.is_finite())
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = f64> + '_ {
+        self.points.iter().map(|(_, value)| *value)
+    }
+
+    // Points from the last `span_secs` up to `end_ms`, as seconds relative to the end (so x runs
+    // from -span_secs to 0) and without gaps, which tui would paint on the top row. Raw samples
+    // are used while they reach back far enough, after that the finest rollup that does, with
+    // each bucket plotted at its middle as `stat`. Only the visible part is looked at.
+    pub fn window(&self, end_ms: f64, span_secs: f64, stat: Stat) -> Vec<(f64, f64)> {
+        let start_ms = end_ms - span_secs * 1000.0;
+        let to_x = |at: f64| (at - end_ms) / 1000.0;
+        if !self.is_rolled_up(span_secs) {
+            let first = self.points.partition_point(|(at, _)| *at < start_ms);
+            return self
+                .points
+                .range(first..)
+                .filter(|(at, value)| *at <= end_ms && value.is_finite())
+                .map(|(at, value)| (to_x(*at), *value))
+                .collect();
+        }
+
+        let rollup = self.rollup_for(span_secs);
+        let half = rollup.bucket_ms / 2.0;
+        let first = rollup.buckets.partition_point(|bucket| bucket.start_ms + half < start_ms);
+        rollup
+            .buckets
+            .range(first..)
+            .filter(|bucket| bucket.start_ms + half <= end_ms)
+            .map(|bucket| (to_x(bucket.start_ms + half), bucket.stat(stat)))
+            .collect()
+    }
+
+    // whether window() has to fall back on buckets for this span, raw samples cover it as long
+    // as none have been dropped yet or the oldest kept is old enough (give or take a sample)
+    pub fn is_rolled_up(&self, span_secs: f64) -> bool {
+        let (Some((oldest, _)), Some(latest)) = (self.points.front(), self.latest_at()) else { return false };
+        let interval = (latest - oldest) / (self.points.len() - 1).max(1) as f64;
+        self.points.len() == self.capacity && latest - oldest + interval < span_secs * 1000.0
+    }
+
+    // the width of the buckets window() uses for this span, if it uses any
+    pub fn bucket_secs(&self, span_secs: f64) -> Option<f64> {
+        self.is_rolled_up(span_secs).then(|| self.rollup_for(span_secs).bucket_ms / 1000.0)
+    }
+
+    fn rollup_for(&self, span_secs: f64) -> &Rollup {
+        let span_ms = span_secs * 1000.0;
+        self.rollups.iter().find(|rollup| rollup.span_ms() >= span_ms).unwrap_or(self.rollups.last().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_series_drops_the_oldest_point() {
+        let mut series = Series::new(3);
+        for t in 0..5 {
+            series.push(1_000 * t, t as f64);
+        }
+        assert_eq!(series.values().collect::<Vec<_>>(), vec![2.0, 3.0, 4.0]);
+        assert_eq!(series.latest_at(), Some(4_000.0));
+    }
+
+    #[test]
+    fn window_is_relative_to_the_end_and_skips_gaps() {
+        let mut series = Series::new(100);
+        for t in 0..10 {
+            series.push(10_000 + 1_000 * t, t as f64);
+        }
+        series.push_gap(20_000);
+        series.push_gap(21_000);
+        assert_eq!(series.values().count(), 11);
+        assert_eq!(series.latest(), Some(9.0));
+
+        assert_eq!(series.window(20_000.0, 3.0, Stat::Avg), vec![(-3.0, 7.0), (-2.0, 8.0), (-1.0, 9.0)]);
+    }
+
+    #[test]
+    fn time_never_runs_backwards() {
+        let mut series = Series::new(10);
+        series.push(5_000, 1.0);
+        series.push(4_000, 2.0);
+        assert_eq!(series.latest_at(), Some(5_000.0));
+    }
+
+    #[test]
+    fn long_windows_fall_back_on_rollups() {
+        // a minute of raw samples, one a second
+        let mut series = Series::new(60);
+        for t in 0..600 {
+            series.push(1_000 * t, (t % 10) as f64);
+        }
+        assert!(!series.is_rolled_up(60.0));
+        assert_eq!(series.window(599_000.0, 60.0, Stat::Avg).len(), 60);
+
+        // ten minutes only survive as 10 second buckets
+        assert_eq!(series.bucket_secs(600.0), Some(10.0));
+        let buckets = series.window(599_000.0, 600.0, Stat::Avg);
+        assert_eq!(buckets.len(), 60);
+        assert_eq!(buckets[0], (-594.0, 4.5));
+        assert_eq!(series.window(599_000.0, 600.0, Stat::Min)[0].1, 0.0);
+        assert_eq!(series.window(599_000.0, 600.0, Stat::Max)[0].1, 9.0);
+
+        // a day uses the 5 minute buckets
+        assert_eq!(series.bucket_secs(86_400.0), Some(300.0));
+        assert_eq!(series.window(599_000.0, 86_400.0, Stat::Avg).len(), 2);
+    }
+}
@@ -0,0 +1,436 @@
+use crate::ingest::IngestSender;
+use crate::shutdown::Shutdown;
+use crate::{ClientControl, ClientId, ClientMessage};
+
+use pitop_protocol::now_millis;
+use serde::{Deserialize, Serialize};
+use util_bundle::{HostInfo, UtilBundle};
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often the replay looks at the clock and its controls
+const REPLAY_TICK: Duration = Duration::from_millis(20);
+const MIN_SPEED: f64 = 0.5;
+const MAX_SPEED: f64 = 16.0;
+pub const SEEK_STEP: Duration = Duration::from_secs(10);
+
+// What a session file holds, one JSON line per message the tui received. Heartbeats and link
+// stats only matter to a live connection and are left out.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Event {
+    Connected(String),
+    Hello(HostInfo),
+    Sample(UtilBundle),
+    Dropped(u64),
+    Goodbye(String),
+    Disconnected,
+}
+
+// Written without taking the message from the tui, reads back as an Event
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum EventRef<'a> {
+    Connected(&'a str),
+    Hello(&'a HostInfo),
+    Sample(&'a UtilBundle),
+    Dropped(u64),
+    Goodbye(&'a str),
+    Disconnected,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    // when the tui took the message off the ingest queue, within a tick of its arrival
+    at_ms: u64,
+    client: ClientId,
+    event: Event,
+}
+
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    at_ms: u64,
+    client: ClientId,
+    event: EventRef<'a>,
+}
+
+// Appends every message to the session file as it comes, so a crash keeps all but the last line
+pub struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    // An earlier recording at the path is left alone, not started over
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path).map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => io::Error::new(e.kind(), format!("{} already exists, record to another file", path.display())),
+            _ => io::Error::new(e.kind(), format!("{}: {}", path.display(), e)),
+        })?;
+        Ok(Recorder { file })
+    }
+
+    pub fn record(&mut self, client: ClientId, message: &ClientMessage) -> io::Result<()> {
+        let event = match message {
+            ClientMessage::Connected(peer, _) => EventRef::Connected(peer),
+            ClientMessage::Hello(host) => EventRef::Hello(host),
+            ClientMessage::Sample(bundle) => EventRef::Sample(bundle),
+            ClientMessage::Dropped(count) => EventRef::Dropped(*count),
+            ClientMessage::Goodbye(reason) => EventRef::Goodbye(reason),
+            ClientMessage::Disconnected => EventRef::Disconnected,
+            ClientMessage::Heartbeat | ClientMessage::Link(_) | ClientMessage::Rewind(_) => return Ok(()),
+        };
+        let mut line = serde_json::to_vec(&EntryRef { at_ms: now_millis(), client, event })?;
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+}
+
+// A recorded session, loaded whole so seeking is just moving an index
+pub struct Session {
+    entries: Vec<Entry>,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> io::Result<Session> {
+        let mut entries = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            if let Ok(entry) = serde_json::from_str::<Entry>(&line?) {
+                entries.push(entry);
+            }
+        }
+        if entries.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} holds no recorded session", path.display())));
+        }
+        entries.sort_by_key(|entry| entry.at_ms);
+        Ok(Session { entries })
+    }
+
+    fn start_ms(&self) -> u64 {
+        self.entries[0].at_ms
+    }
+
+    fn duration_ms(&self) -> f64 {
+        (self.entries[self.entries.len() - 1].at_ms - self.start_ms()) as f64
+    }
+
+    // index of the first entry after `position_ms` into the session
+    fn index_after(&self, position_ms: f64) -> usize {
+        let start = self.start_ms();
+        self.entries.partition_point(|entry| (entry.at_ms - start) as f64 <= position_ms)
+    }
+
+    // Who was connected once the first `end` entries had been received, and with which hello
+    fn connected_at(&self, end: usize) -> BTreeMap<ClientId, (String, Option<HostInfo>)> {
+        let mut connected = BTreeMap::new();
+        for entry in &self.entries[..end] {
+            track(&mut connected, entry);
+        }
+        connected
+    }
+}
+
+fn track(connected: &mut BTreeMap<ClientId, (String, Option<HostInfo>)>, entry: &Entry) {
+    match &entry.event {
+        Event::Connected(peer) => {
+            connected.insert(entry.client, (peer.clone(), None));
+        }
+        Event::Hello(host) => {
+            if let Some((_, hello)) = connected.get_mut(&entry.client) {
+                *hello = Some(host.clone());
+            }
+        }
+        Event::Disconnected => {
+            connected.remove(&entry.client);
+        }
+        _ => {}
+    }
+}
+
+struct PlaybackState {
+    // into the session
+    position_ms: f64,
+    duration_ms: f64,
+    speed: f64,
+    paused: bool,
+    // moved by hand since the replay last looked, with how much to show of what led up to it
+    seeked: Option<f64>,
+}
+
+// The tui's handle on a running replay
+#[derive(Clone)]
+pub struct Playback {
+    state: Arc<Mutex<PlaybackState>>,
+}
+
+impl Playback {
+    fn new(duration_ms: f64) -> Playback {
+        Playback {
+            state: Arc::new(Mutex::new(PlaybackState {
+                position_ms: 0.0,
+                duration_ms,
+                speed: 1.0,
+                paused: false,
+                seeked: None,
+            })),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, PlaybackState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn toggle_pause(&self) {
+        let mut state = self.state();
+        state.paused = !state.paused;
+    }
+
+    pub fn faster(&self) {
+        let mut state = self.state();
+        state.speed = (state.speed * 2.0).min(MAX_SPEED);
+    }
+
+    pub fn slower(&self) {
+        let mut state = self.state();
+        state.speed = (state.speed / 2.0).max(MIN_SPEED);
+    }
+
+    // window is how far back the charts get filled in from the new position
+    pub fn seek(&self, forward: bool, by: Duration, window: Duration) {
+        let mut state = self.state();
+        let by = by.as_millis() as f64;
+        let position = if forward { state.position_ms + by } else { state.position_ms - by };
+        state.position_ms = position.clamp(0.0, state.duration_ms);
+        state.seeked = Some(window.as_millis() as f64);
+    }
+
+    // e.g. "replay 01:10 / 05:00 at 2x, paused"
+    pub fn status(&self) -> String {
+        let state = self.state();
+        let clock = |ms: f64| {
+            let secs = (ms / 1000.0) as u64;
+            format!("{:02}:{:02}", secs / 60, secs % 60)
+        };
+        let mut status = format!("replay {} / {} at {}x", clock(state.position_ms), clock(state.duration_ms), state.speed);
+        if state.paused {
+            status.push_str(", paused");
+        } else if state.position_ms >= state.duration_ms {
+            status.push_str(", finished");
+        }
+        status
+    }
+
+    // Moves the position on by however long has passed at the current speed. Returns the new
+    // position and, when it was moved by hand since the last call, the window to fill in.
+    fn advance(&self, elapsed: Duration) -> (f64, Option<f64>) {
+        let mut state = self.state();
+        if !state.paused {
+            state.position_ms = (state.position_ms + elapsed.as_millis() as f64 * state.speed).min(state.duration_ms);
+        }
+        (state.position_ms, state.seeked.take())
+    }
+}
+
+// What the tui gets for a recorded message. Samples are made to look freshly taken, keeping how
+// long they took to arrive, so the charts draw them as live ones.
+fn to_message(event: &Event, now_ms: u64, at_ms: u64) -> ClientMessage {
+    match event {
+        // nobody to send controls to
+        Event::Connected(peer) => ClientMessage::Connected(peer.clone(), ClientControl::new(Box::new(io::sink()))),
+        Event::Hello(host) => ClientMessage::Hello(host.clone()),
+        Event::Sample(bundle) => {
+            let mut bundle = bundle.clone();
+            let taken_ms = match bundle.sampled_at_ms {
+                0 => at_ms,
+                sampled_at_ms => sampled_at_ms,
+            };
+            bundle.sampled_at_ms = now_ms.saturating_sub(at_ms.saturating_sub(taken_ms));
+            ClientMessage::Sample(bundle)
+        }
+        Event::Dropped(count) => ClientMessage::Dropped(*count),
+        Event::Goodbye(reason) => ClientMessage::Goodbye(reason.clone()),
+        Event::Disconnected => ClientMessage::Disconnected,
+    }
+}
+
+// Feeds a recorded session to the tui through the same queue live clients use, paced by the
+// playback controls. After a seek everyone connected is disconnected and the clients connected at
+// the new position reconnect, which the tui maps back onto their old tabs by hostname. Each tab's
+// charts are then replaced with the window of samples leading up to the new position.
+pub fn replay(session: Session, sender: IngestSender, shutdown: &Shutdown) -> (Playback, thread::JoinHandle<()>) {
+    let playback = Playback::new(session.duration_ms());
+    let controls = playback.clone();
+    let shutdown = shutdown.clone();
+    let handle = thread::spawn(move || {
+        let mut next = 0;
+        let mut connected = BTreeMap::new();
+        let mut last = Instant::now();
+        while !shutdown.requested() {
+            let (position_ms, seeked) = controls.advance(last.elapsed());
+            last = Instant::now();
+            let now_ms = now_millis();
+
+            if let Some(window_ms) = seeked {
+                let was_connected = std::mem::take(&mut connected);
+                next = session.index_after(position_ms);
+                connected = session.connected_at(next);
+                let first = session.index_after(position_ms - window_ms);
+                // timed as if they had just been played up to here
+                let position_at = session.start_ms() + position_ms as u64;
+                let window = |client: ClientId| {
+                    let entries = session.entries[first..next].iter().filter(|entry| entry.client == client);
+                    let samples = entries.filter_map(|entry| {
+                        match to_message(&entry.event, now_ms.saturating_sub(position_at - entry.at_ms), entry.at_ms) {
+                            ClientMessage::Sample(bundle) => Some(bundle),
+                            _ => None,
+                        }
+                    });
+                    ClientMessage::Rewind(samples.collect())
+                };
+
+                for &client in was_connected.keys() {
+                    // gone at the new position, its tab shows what it had sent by then
+                    if !connected.contains_key(&client) {
+                        let _ = sender.send((client, window(client)));
+                    }
+                    let _ = sender.send((client, ClientMessage::Disconnected));
+                }
+                for (&client, (peer, hello)) in &connected {
+                    let _ = sender.send((client, ClientMessage::Connected(peer.clone(), ClientControl::new(Box::new(io::sink())))));
+                    if let Some(host) = hello {
+                        let _ = sender.send((client, ClientMessage::Hello(host.clone())));
+                    }
+                    let _ = sender.send((client, window(client)));
+                }
+            }
+
+            let end = session.index_after(position_ms);
+            for entry in &session.entries[next.min(end)..end] {
+                track(&mut connected, entry);
+                if sender.send((entry.client, to_message(&entry.event, now_ms, entry.at_ms))).is_err() {
+                    // the tui is gone
+                    return;
+                }
+            }
+            next = next.max(end);
+            thread::sleep(REPLAY_TICK);
+        }
+    });
+    (playback, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::Hosts;
+    use crate::ingest::{self, Backpressure};
+
+    fn record(path: &Path) {
+        let mut recorder = Recorder::create(path).unwrap();
+        let control = ClientControl::new(Box::new(io::sink()));
+        recorder.record(7, &ClientMessage::Connected("10.0.0.1:5000".to_string(), control)).unwrap();
+        recorder.record(7, &ClientMessage::Heartbeat).unwrap();
+        recorder.record(7, &ClientMessage::Hello(HostInfo { hostname: "workstation".to_string(), ..HostInfo::default() })).unwrap();
+        recorder.record(7, &ClientMessage::Sample(UtilBundle { sampled_at_ms: 1, cpu_usage: vec![42.0], ..Default::default() })).unwrap();
+        recorder.record(7, &ClientMessage::Disconnected).unwrap();
+    }
+
+    #[test]
+    fn replay_feeds_the_recorded_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.pitop");
+        record(&path);
+        let session = Session::load(&path).unwrap();
+        assert_eq!(session.entries.len(), 4);
+        assert_eq!(Recorder::create(&path).err().map(|e| e.kind()), Some(io::ErrorKind::AlreadyExists));
+        assert_eq!(Session::load(&path).unwrap().entries.len(), 4);
+
+        let (sender, receiver) = ingest::channel(64, Backpressure::DropNewest);
+        let shutdown = Shutdown::new();
+        let (playback, handle) = replay(session, sender, &shutdown);
+
+        let mut received = Vec::new();
+        while received.len() < 4 {
+            received.extend(receiver.drain(Duration::from_secs(1)));
+        }
+        shutdown.request();
+        handle.join().unwrap();
+
+        assert!(received.iter().all(|(client, _)| *client == 7));
+        assert!(matches!(&received[0].1, ClientMessage::Connected(peer, _) if peer == "10.0.0.1:5000"));
+        assert!(matches!(&received[1].1, ClientMessage::Hello(host) if host.hostname == "workstation"));
+        assert!(matches!(&received[2].1, ClientMessage::Sample(bundle) if bundle.cpu_usage == vec![42.0]));
+        assert!(matches!(received[3].1, ClientMessage::Disconnected));
+        assert!(playback.status().starts_with("replay 00:00 / 00:00 at 1x"));
+    }
+
+    #[test]
+    fn seeking_back_refills_the_charts_with_what_led_up_to_it() {
+        let entry = |at_ms: u64, event: Event| Entry { at_ms, client: 1, event };
+        let mut entries = vec![
+            entry(0, Event::Connected("10.0.0.1:5000".to_string())),
+            entry(0, Event::Hello(HostInfo { hostname: "pi".to_string(), ..HostInfo::default() })),
+        ];
+        entries.extend((1..=30).map(|secs| entry(secs * 1000, Event::Sample(UtilBundle { cpu_usage: vec![secs as f32], ..Default::default() }))));
+        let (sender, receiver) = ingest::channel(64, Backpressure::DropNewest);
+        let shutdown = Shutdown::new();
+        let (playback, handle) = replay(Session { entries }, sender, &shutdown);
+        playback.toggle_pause();
+
+        // to the end, then back to 10s in with a 5s window
+        let mut hosts = Hosts::new(Duration::from_secs(60), Duration::from_secs(60));
+        for (forward, by) in [(true, 60), (false, 20)] {
+            playback.seek(forward, Duration::from_secs(by), Duration::from_secs(5));
+            loop {
+                let received = receiver.drain(Duration::from_secs(1));
+                let rewound = received.iter().any(|(_, message)| matches!(message, ClientMessage::Rewind(_)));
+                received.into_iter().for_each(|(client, message)| hosts.on_message(client, message));
+                if rewound {
+                    break;
+                }
+            }
+        }
+        shutdown.request();
+        handle.join().unwrap();
+
+        assert_eq!(hosts.apps.len(), 1);
+        assert_eq!(hosts.apps[0].label(), "pi");
+        assert_eq!(hosts.apps[0].cpu_util[0].values().collect::<Vec<f64>>(), vec![6.0, 7.0, 8.0, 9.0, 10.0]);
+    }
+
+    #[test]
+    fn samples_play_back_as_if_just_taken() {
+        let event = Event::Sample(UtilBundle { sampled_at_ms: 9_000, ..Default::default() });
+        // took a second to arrive back then
+        match to_message(&event, 100_000, 10_000) {
+            ClientMessage::Sample(bundle) => assert_eq!(bundle.sampled_at_ms, 99_000),
+            _ => panic!("expected a sample"),
+        }
+    }
+
+    #[test]
+    fn speed_stays_in_range_and_seeking_stays_in_the_session() {
+        let playback = Playback::new(60_000.0);
+        for _ in 0..10 {
+            playback.faster();
+        }
+        assert!(playback.status().ends_with("at 16x"));
+        for _ in 0..10 {
+            playback.slower();
+        }
+        assert!(playback.status().ends_with("at 0.5x"));
+
+        playback.seek(true, Duration::from_secs(90), Duration::from_secs(60));
+        assert_eq!(playback.advance(Duration::ZERO), (60_000.0, Some(60_000.0)));
+        playback.seek(false, SEEK_STEP, Duration::from_secs(60));
+        playback.toggle_pause();
+        assert_eq!(playback.advance(Duration::from_secs(5)), (50_000.0, Some(60_000.0)));
+        assert_eq!(playback.advance(Duration::from_secs(5)), (50_000.0, None));
+        assert_eq!(playback.status(), "replay 00:50 / 01:00 at 0.5x, paused");
+    }
+}
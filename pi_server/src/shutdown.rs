@@ -0,0 +1,64 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use mio::Waker;
+use signal_hook::consts::{SIGHUP, TERM_SIGNALS};
+use signal_hook::flag;
+
+// A signal only tells the tui, which notices on its next tick. Once it has restored the terminal
+// and said goodbye to the clients, like when quit from the keyboard, it requests shutdown for
+// everyone else, waking the loops that would otherwise sleep through it.
+#[derive(Clone)]
+pub struct Shutdown {
+    signalled: Arc<AtomicBool>,
+    requested: Arc<AtomicBool>,
+    wakers: Arc<Mutex<Vec<Arc<Waker>>>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            signalled: Arc::new(AtomicBool::new(false)),
+            requested: Arc::new(AtomicBool::new(false)),
+            wakers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Ctrl-C, kill and a closed terminal all ask for a clean exit, a second one while we are
+    // still shutting down exits right away
+    pub fn on_signals(&self) -> io::Result<()> {
+        for &signal in TERM_SIGNALS.iter().chain(&[SIGHUP]) {
+            flag::register_conditional_shutdown(signal, 1, Arc::clone(&self.signalled))?;
+            flag::register(signal, Arc::clone(&self.signalled))?;
+        }
+        Ok(())
+    }
+
+    pub fn signalled(&self) -> bool {
+        self.signalled.load(Ordering::SeqCst)
+    }
+
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        if let Ok(wakers) = self.wakers.lock() {
+            for waker in wakers.iter() {
+                let _ = waker.wake();
+            }
+        }
+    }
+
+    pub fn wake_on_request(&self, waker: Arc<Waker>) {
+        if let Ok(mut wakers) = self.wakers.lock() {
+            wakers.push(waker);
+        }
+        // requested before we got here
+        if self.requested() {
+            self.request();
+        }
+    }
+}
@@ -1,24 +1,48 @@
-use crate::ui::ColorGenerator;
-use crate::{UtilBundle, ui::draw_ui, app::App, POLLING_PERIOD_MILLIS};
+use crate::ui::{grid_columns, ColorGenerator, Screen, View};
+use crate::crash::CrashReport;
+use crate::session::SEEK_STEP;
+use crate::ingest::IngestReceiver;
+use crate::shutdown::Shutdown;
+use crate::log;
+use crate::{ui::draw_hosts, app::App, hosts::Hosts, ClientMessage, POLLING_PERIOD_MILLIS};
 
-use std::sync::mpsc::{Receiver};
 use std::time;
 
-use crossterm::event::{self, Event, KeyCode};
-use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, LeaveAlternateScreen};
 use crossterm::{execute, Result};
 use tui::backend::CrosstermBackend;
 use tui::Terminal;
+use util_bundle::MetricGroup;
+
+// Keys that steer the selected client's sampling
+fn on_control_key(app: &mut App, code: KeyCode) {
+    match code {
+        KeyCode::Char('+') | KeyCode::Char('=') => app.set_sample_interval(app.sample_interval_ms / 2),
+        KeyCode::Char('-') => app.set_sample_interval(app.sample_interval_ms * 2),
+        KeyCode::Char('s') => app.request_sample(),
+        KeyCode::Char('c') => app.toggle_metric_group(MetricGroup::Cpu),
+        KeyCode::Char('m') => app.toggle_metric_group(MetricGroup::Memory),
+        KeyCode::Char('g') => app.toggle_metric_group(MetricGroup::Gpu),
+        KeyCode::Char('n') => app.toggle_metric_group(MetricGroup::Network),
+        KeyCode::Char('d') => app.toggle_metric_group(MetricGroup::Disk),
+        _ => {}
+    }
+}
 
 fn run_app(
     terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>,
-    app: &mut App,
-    datastream_in: Receiver<UtilBundle>,
+    hosts: &mut Hosts,
+    datastream_in: IngestReceiver,
+    shutdown: &Shutdown,
+    crash: &CrashReport,
 ) -> Result<()> {
     let mut color_gen: ColorGenerator = ColorGenerator::new();
-    Ok(loop {
+    let mut screen = Screen::Detail;
+    let mut view = View::Minute;
+    while !shutdown.signalled() {
         terminal.draw(|f| {
-            draw_ui(f, app, &mut color_gen);
+            draw_hosts(f, hosts, screen, view, &mut color_gen);
         })?;
 
         // TODO: Update tick-rate logic to be more accurate
@@ -27,39 +51,96 @@ fn run_app(
         let timeout = tick_rate;
         if crossterm::event::poll(timeout)? {
             if let Event::Key(key) = event::read()? {
-                if let KeyCode::Char('q') = key.code {
+                let columns = grid_columns(hosts.apps.len());
+                // raw mode turns Ctrl-C into a key press instead of a signal
+                if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
                     return Ok(());
                 }
+                if let Some(playback) = &hosts.playback {
+                    match key.code {
+                        KeyCode::Char(' ') => playback.toggle_pause(),
+                        KeyCode::Char(',') => playback.seek(false, SEEK_STEP, time::Duration::from_secs_f64(view.span_secs())),
+                        KeyCode::Char('.') => playback.seek(true, SEEK_STEP, time::Duration::from_secs_f64(view.span_secs())),
+                        KeyCode::Char('<') => playback.slower(),
+                        KeyCode::Char('>') => playback.faster(),
+                        _ => {}
+                    }
+                }
+                match (screen, key.code) {
+                    (_, KeyCode::Char('q')) => return Ok(()),
+                    (_, KeyCode::Tab) | (_, KeyCode::Right) => hosts.select_next(),
+                    (_, KeyCode::BackTab) | (_, KeyCode::Left) => hosts.select_previous(),
+                    (_, KeyCode::Char(c @ '1'..='9')) => hosts.select(c as usize - '1' as usize),
+                    (Screen::Overview, KeyCode::Up) => hosts.select_up(columns),
+                    (Screen::Overview, KeyCode::Down) => hosts.select_down(columns),
+                    (Screen::Overview, KeyCode::Enter) => screen = Screen::Detail,
+                    (Screen::Detail, KeyCode::Char('o')) | (Screen::Detail, KeyCode::Esc) => screen = Screen::Overview,
+                    (Screen::Detail, KeyCode::Char('v')) => view = view.next(),
+                    (_, code) => {
+                        if let Some(app) = hosts.selected_app() {
+                            on_control_key(app, code);
+                        }
+                    }
+                }
             }
         }
-        if let Ok(datapoint) = datastream_in.recv_timeout(tick_rate) {
-            app.on_tick(datapoint);
-        } else {
-            // println!("Generate 0 datapoint");
-            app.on_tick(UtilBundle::new());
+        // several clients may have sent since the last tick, catch up on all of them
+        for (client, message) in datastream_in.drain(tick_rate) {
+            if let ClientMessage::Sample(bundle) = &message {
+                crash.record(client, bundle);
+            }
+            hosts.on_message(client, message);
         }
+        hosts.check_staleness(time::Instant::now());
+        hosts.save_history(Some(time::Instant::now()));
 
         terminal.clear()?;
-    })
+    }
+    Ok(())
+}
+
+// Safe to call more than once, the panic hook and the guard may both get to it
+pub fn restore_terminal() {
+    let _ = execute!(std::io::stdout(), LeaveAlternateScreen, crossterm::cursor::Show);
+    let _ = disable_raw_mode();
+    log::to_status_bar(false);
+}
+
+// Puts the terminal back and lets everyone else know the tui is done, however the tui ends,
+// a panic included
+struct TuiGuard {
+    shutdown: Shutdown,
 }
 
-pub fn tui(datastream_in: Receiver<UtilBundle>) -> Result<()> {
+impl Drop for TuiGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+        self.shutdown.request();
+    }
+}
+
+pub fn tui(
+    datastream_in: IngestReceiver,
+    mut hosts: Hosts,
+    shutdown: Shutdown,
+    crash: CrashReport,
+) -> Result<()> {
     // println!("tui");
 
+    let guard = TuiGuard { shutdown: shutdown.clone() };
     enable_raw_mode()?;
     let mut stdout = std::io::stdout();
     execute!(stdout, crossterm::terminal::EnterAlternateScreen)?;
+    log::to_status_bar(true);
 
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let mut app = App::new();
-    run_app(&mut terminal, &mut app, datastream_in)?;
+    let result = run_app(&mut terminal, &mut hosts, datastream_in, &shutdown, &crash);
 
-    execute!(
-        terminal.backend_mut(),
-        crossterm::terminal::LeaveAlternateScreen
-    )?;
-    disable_raw_mode()?;
-    Ok(())
+    hosts.save_history(None);
+    // queued before the guard wakes the event loop, so they go out before it closes the sockets
+    hosts.say_goodbye("server shutting down");
+    drop(guard);
+    result
 }
@@ -0,0 +1,163 @@
+use crate::access::AccessList;
+use crate::ingest::IngestSender;
+use crate::shutdown::Shutdown;
+use crate::{to_message, ClientControl, ClientId, ClientMessage, CLIENT_TIMEOUT_MILLIS};
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use pitop_protocol::datagram::{self, DatagramStream, SeqTracker, MAX_DATAGRAM_LEN};
+use pitop_protocol::MessageKind;
+
+// how often silent peers are swept out, also the longest a receive blocks
+const SWEEP_PERIOD_MILLIS: u64 = 1_000;
+const LINK_REPORT_PERIOD_MILLIS: u64 = 1_000;
+// rejected addresses are logged once, within reason
+const MAX_REMEMBERED_REJECTIONS: usize = 1024;
+
+// There are no connections over UDP, a client is whoever sends from a given address. It counts
+// as connected from its first datagram until it has been silent for the client timeout.
+struct Peer {
+    client: ClientId,
+    last_seen: Instant,
+    seq: SeqTracker,
+    last_report: Instant,
+}
+
+struct UdpPeers<'a> {
+    socket: &'a UdpSocket,
+    access: &'a AccessList,
+    max_clients: Option<usize>,
+    producer: IngestSender,
+    peers: HashMap<SocketAddr, Peer>,
+    rejected: HashSet<SocketAddr>,
+    next_client: ClientId,
+}
+
+impl UdpPeers<'_> {
+    fn admit(&mut self, addr: SocketAddr) -> Result<(), String> {
+        self.access.check(addr.ip())?;
+        if self.max_clients.is_some_and(|max| self.peers.len() >= max) {
+            return Err(format!("already serving {} clients", self.peers.len()));
+        }
+        Ok(())
+    }
+
+    // Returns false once the tui is gone
+    fn on_datagram(&mut self, addr: SocketAddr, datagram: &[u8]) -> bool {
+        if !self.peers.contains_key(&addr) {
+            if let Err(reason) = self.admit(addr) {
+                if self.rejected.len() >= MAX_REMEMBERED_REJECTIONS {
+                    self.rejected.clear();
+                }
+                if self.rejected.insert(addr) {
+                    log!("Rejected {}: {}", addr, reason);
+                }
+                return true;
+            }
+
+            let control = match self.socket.try_clone() {
+                Ok(socket) => ClientControl::new(Box::new(DatagramStream::to(socket, addr))),
+                Err(e) => {
+                    log!("Rejected {}: {}", addr, e);
+                    return true;
+                }
+            };
+            let client = self.next_client;
+            self.next_client += 1;
+            if self.producer.send((client, ClientMessage::Connected(addr.to_string(), control))).is_err() {
+                return false;
+            }
+            let now = Instant::now();
+            self.peers.insert(addr, Peer { client, last_seen: now, seq: SeqTracker::new(), last_report: now });
+        }
+
+        let envelope = match datagram::decode(datagram) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                log!("Dropping datagram from {}: {}", addr, e);
+                return true;
+            }
+        };
+
+        let peer = self.peers.get_mut(&addr).unwrap();
+        peer.last_seen = Instant::now();
+        // a client that reconnects from the same address starts a new envelope writer
+        if envelope.kind == MessageKind::Hello && envelope.seq == 0 {
+            peer.seq.restart();
+        }
+        peer.seq.observe(envelope.seq);
+
+        let client = peer.client;
+        if peer.last_report.elapsed() >= Duration::from_millis(LINK_REPORT_PERIOD_MILLIS) {
+            peer.last_report = Instant::now();
+            if self.producer.send((client, ClientMessage::Link(peer.seq.stats()))).is_err() {
+                return false;
+            }
+        }
+        match to_message(envelope) {
+            Some(message) => self.producer.send((client, message)).is_ok(),
+            None => true,
+        }
+    }
+
+    fn sweep(&mut self, now: Instant) -> bool {
+        let timeout = Duration::from_millis(CLIENT_TIMEOUT_MILLIS);
+        let gone: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| now.duration_since(peer.last_seen) > timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in gone {
+            let peer = self.peers.remove(&addr).unwrap();
+            if self.producer.send((peer.client, ClientMessage::Disconnected)).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub fn process_incoming_udp(
+    socket: UdpSocket,
+    access: &AccessList,
+    max_clients: Option<usize>,
+    producer: IngestSender,
+    shutdown: &Shutdown,
+) -> io::Result<()> {
+    socket.set_read_timeout(Some(Duration::from_millis(SWEEP_PERIOD_MILLIS)))?;
+    let mut peers = UdpPeers {
+        socket: &socket,
+        access,
+        max_clients,
+        producer,
+        peers: HashMap::new(),
+        rejected: HashSet::new(),
+        next_client: 0,
+    };
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    let mut last_sweep = Instant::now();
+
+    while !shutdown.requested() {
+        match socket.recv_from(&mut buf) {
+            Ok((len, addr)) => {
+                if !peers.on_datagram(addr, &buf[..len]) {
+                    return Ok(());
+                }
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(e) => log!("UDP receive failed: {}", e),
+        }
+
+        if last_sweep.elapsed() >= Duration::from_millis(SWEEP_PERIOD_MILLIS) {
+            last_sweep = Instant::now();
+            if !peers.sweep(last_sweep) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,12 +1,19 @@
 use crate::App;
+use crate::app::Health;
+use crate::hosts::Hosts;
+use crate::log;
+use crate::series::Stat;
 
+use std::collections::BTreeMap;
+
+use pitop_protocol::now_millis;
 use rand::seq::SliceRandom;
 use tui::backend::{Backend, CrosstermBackend};
 use tui::layout::{Constraint, Direction, Layout, Rect};
 use tui::style::{Color, Modifier, Style};
 use tui::symbols::{self};
-use tui::text::Span;
-use tui::widgets::{Axis, Block, Borders, Chart, Dataset, Gauge};
+use tui::text::{Span, Spans};
+use tui::widgets::{Axis, Block, BorderType, Borders, Chart, Dataset, Gauge, Paragraph, Sparkline, Tabs};
 use tui::{Frame};
 
 pub struct ColorGenerator {
@@ -20,10 +27,6 @@ impl ColorGenerator {
 
     // memoized so that each cpu core always has the same color as before
     pub fn idx_to_color_persistant(&mut self, idx: usize) -> Color {
-        if idx < self.idx_to_color.len() {
-            self.idx_to_color[idx];
-        }
-
         while idx >= self.idx_to_color.len() {
             self.idx_to_color.push(rand_color());
         }
@@ -51,17 +54,265 @@ fn rand_color() -> Color {
         Color::White,
     ];
 
-    color_wheel.choose(&mut rand::thread_rng()).unwrap().clone()
+    *color_wheel.choose(&mut rand::thread_rng()).unwrap()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Screen {
+    Overview,
+    Detail,
+}
+
+// How much history the detail charts show
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum View {
+    Minute,
+    TenMinutes,
+    Hour,
+    Day,
+}
+
+impl View {
+    pub fn next(self) -> View {
+        match self {
+            View::Minute => View::TenMinutes,
+            View::TenMinutes => View::Hour,
+            View::Hour => View::Day,
+            View::Day => View::Minute,
+        }
+    }
+
+    pub fn span_secs(self) -> f64 {
+        match self {
+            View::Minute => 60.0,
+            View::TenMinutes => 600.0,
+            View::Hour => 3_600.0,
+            View::Day => 86_400.0,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            View::Minute => "1m",
+            View::TenMinutes => "10m",
+            View::Hour => "1h",
+            View::Day => "24h",
+        }
+    }
+}
+
+pub fn draw_hosts(
+    f: &mut Frame<'_, CrosstermBackend<std::io::Stdout>>,
+    hosts: &mut Hosts,
+    screen: Screen,
+    view: View,
+    color_gen: &mut ColorGenerator,
+) {
+    let screen_and_status = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+        .split(f.size());
+    draw_status_bar(hosts, f, screen_and_status[1]);
+
+    if screen == Screen::Overview {
+        draw_overview(hosts, f, screen_and_status[0]);
+        return;
+    }
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(1)
+        .constraints([Constraint::Length(3), Constraint::Min(0)].as_ref())
+        .split(screen_and_status[0]);
+
+    draw_host_tabs(hosts, f, chunks[0]);
+    match hosts.selected_app() {
+        Some(app) => draw_ui(f, app, view, color_gen, chunks[1]),
+        None => {
+            let waiting = Paragraph::new("Waiting for clients...")
+                .block(Block::default().borders(Borders::ALL));
+            f.render_widget(waiting, chunks[1]);
+        }
+    }
+}
+
+fn draw_host_tabs<B: Backend>(hosts: &Hosts, f: &mut Frame<B>, area: Rect) {
+    let titles = hosts
+        .apps
+        .iter()
+        .enumerate()
+        .map(|(idx, app)| {
+            let (status, color) = match app.health() {
+                Health::Disconnected if app.left => (" (left)", Color::DarkGray),
+                Health::Disconnected => (" (disconnected)", Color::DarkGray),
+                Health::Stale => (" (stale)", Color::Magenta),
+                _ => ("", Color::White),
+            };
+            let loss = app.link.map(|link| format!(" loss {:.1}%", link.loss_rate() * 100.0)).unwrap_or_default();
+            Spans::from(Span::styled(format!("{} {}{}{}", idx + 1, app.label(), status, loss), Style::default().fg(color)))
+        })
+        .collect();
+
+    let tabs = Tabs::new(titles)
+        .block(Block::default().title("Hosts (Tab/1-9 switch, o overview, v history, +/- rate, s sample, c/m/g/n/d toggle metrics, q quit)").borders(Borders::ALL))
+        .select(hosts.selected)
+        .highlight_style(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD));
+    f.render_widget(tabs, area);
+}
+
+// Samples the display fell too far behind on, in total and for the selected host, and whatever
+// the server last had to report
+fn draw_status_bar<B: Backend>(hosts: &Hosts, f: &mut Frame<B>, area: Rect) {
+    let total = hosts.dropped();
+    let mut status = String::new();
+    if let Some(playback) = &hosts.playback {
+        status.push_str(&format!(" {} (space pause, ,/. seek, </> speed) |", playback.status()));
+    }
+    status.push_str(&format!(" Dropped {} samples", total));
+    if let Some(app) = hosts.apps.get(hosts.selected) {
+        status.push_str(&format!(" ({} from {})", app.dropped, app.label()));
+    }
+    status.push_str(&format!(" | ingest queue: {}", hosts.queue));
+    let latest = log::latest();
+    if let Some(message) = &latest {
+        status.push_str(&format!(" | {}", message));
+    }
+    let color = if total > 0 || latest.is_some() { Color::Red } else { Color::DarkGray };
+    f.render_widget(Paragraph::new(status).style(Style::default().fg(color)), area);
+}
+
+// columns of a roughly square grid that fits every host
+pub fn grid_columns(n_hosts: usize) -> usize {
+    let mut columns = 1;
+    while columns * columns < n_hosts {
+        columns += 1;
+    }
+    columns
+}
+
+fn health_color(health: Health) -> Color {
+    match health {
+        Health::Ok => Color::Green,
+        Health::Warn => Color::Yellow,
+        Health::Critical => Color::Red,
+        Health::Stale => Color::Magenta,
+        Health::Disconnected => Color::DarkGray,
+    }
+}
+
+fn draw_overview<B: Backend>(hosts: &Hosts, f: &mut Frame<B>, area: Rect) {
+    let block = Block::default()
+        .title("Overview (arrows to select, Enter for details, q to quit)")
+        .borders(Borders::ALL);
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    if hosts.apps.is_empty() {
+        f.render_widget(Paragraph::new("Waiting for clients..."), inner);
+        return;
+    }
+
+    let columns = grid_columns(hosts.apps.len());
+    let rows = hosts.apps.len().div_ceil(columns);
+    let row_areas = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(vec![Constraint::Ratio(1, rows as u32); rows])
+        .split(inner);
+
+    for (row, row_area) in row_areas.into_iter().enumerate() {
+        let tile_areas = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints(vec![Constraint::Ratio(1, columns as u32); columns])
+            .split(row_area);
+        for (column, tile_area) in tile_areas.into_iter().enumerate() {
+            let idx = row * columns + column;
+            if let Some(app) = hosts.apps.get(idx) {
+                draw_host_tile(app, idx == hosts.selected, f, tile_area);
+            }
+        }
+    }
+}
+
+fn draw_host_tile<B: Backend>(app: &App, selected: bool, f: &mut Frame<B>, area: Rect) {
+    let color = health_color(app.health());
+    let block = Block::default()
+        .title(Span::styled(app.label(), Style::default().fg(color).add_modifier(Modifier::BOLD)))
+        .borders(Borders::ALL)
+        .border_type(if selected { BorderType::Thick } else { BorderType::Plain })
+        .border_style(Style::default().fg(color));
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Length(1),
+                Constraint::Length(1),
+                Constraint::Length(1),
+                Constraint::Min(0),
+            ]
+            .as_ref(),
+        )
+        .split(inner);
+
+    let mut summary = format!("CPU {:.1}%", app.latest_cpu_average());
+    if let Some(link) = app.link {
+        summary.push_str(&format!("  loss {:.1}%", link.loss_rate() * 100.0));
+    }
+    let cpu = Paragraph::new(summary).style(Style::default().fg(color));
+    f.render_widget(cpu, rows[0]);
+
+    let mem_ratio = app.latest_mem_ratio();
+    let mem = Gauge::default()
+        .gauge_style(Style::default().fg(Color::Yellow))
+        .label(format!("Mem {:.0}%", mem_ratio * 100.0))
+        .ratio(mem_ratio);
+    f.render_widget(mem, rows[1]);
+
+    let gpu_ratio = app.latest_gpu_ratio();
+    let gpu = Gauge::default()
+        .gauge_style(Style::default().fg(Color::Green))
+        .label(format!("GPU {:.0}%", gpu_ratio * 100.0))
+        .ratio(gpu_ratio);
+    f.render_widget(gpu, rows[2]);
+
+    // newest samples that fit, tx + rx in kbps
+    let mut network: Vec<u64> = app
+        .network_tx
+        .values()
+        .rev()
+        .zip(app.network_rx.values().rev())
+        .filter(|(tx, rx)| tx.is_finite() && rx.is_finite())
+        .map(|(tx, rx)| (tx + rx) as u64)
+        .take(rows[3].width as usize)
+        .collect();
+    network.reverse();
+    let sparkline = Sparkline::default()
+        .style(Style::default().fg(Color::Cyan))
+        .data(&network);
+    f.render_widget(sparkline, rows[3]);
+}
+
+// The highest (or lowest) of several series at each x, e.g. the busiest core in each bucket
+fn envelope(series: &[Vec<(f64, f64)>], pick: fn(f64, f64) -> f64) -> Vec<(f64, f64)> {
+    let mut by_x: BTreeMap<i64, (f64, f64)> = BTreeMap::new();
+    for &(x, y) in series.iter().flatten() {
+        by_x.entry((x * 1000.0).round() as i64).and_modify(|(_, v)| *v = pick(*v, y)).or_insert((x, y));
+    }
+    by_x.into_values().collect()
 }
 
 pub fn draw_ui(
     f: &mut Frame<'_, CrosstermBackend<std::io::Stdout>>,
     app: &mut App,
+    view: View,
     color_gen: &mut ColorGenerator,
+    area: Rect,
 ) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
-        .margin(2)
+        .margin(1)
         .constraints(
             [
                 Constraint::Percentage(50),
@@ -70,54 +321,80 @@ pub fn draw_ui(
             ]
             .as_ref(),
         )
-        .split(f.size());
+        .split(area);
+
+    // a client whose clock runs ahead of ours would otherwise plot off the right edge
+    let end = app.latest_at().map_or(now_millis() as f64, |latest| latest.max(now_millis() as f64));
+    let span = view.span_secs();
+    let cpu_points: Vec<Vec<(f64, f64)>> = app.cpu_util.iter().map(|core| core.window(end, span, Stat::Avg)).collect();
+    let network_tx = app.network_tx.window(end, span, Stat::Avg);
+    let network_rx = app.network_rx.window(end, span, Stat::Avg);
+
+    // bucket averages hide spikes, so rolled up views also show how far the cores went either way
+    let bucket_secs = app.cpu_util.first().and_then(|core| core.bucket_secs(span));
+    let cpu_range = match bucket_secs {
+        Some(_) => {
+            let max: Vec<Vec<(f64, f64)>> = app.cpu_util.iter().map(|core| core.window(end, span, Stat::Max)).collect();
+            let min: Vec<Vec<(f64, f64)>> = app.cpu_util.iter().map(|core| core.window(end, span, Stat::Min)).collect();
+            vec![("max", envelope(&max, f64::max)), ("min", envelope(&min, f64::min))]
+        }
+        None => Vec::new(),
+    };
 
     let mut cpu_datasets: Vec<Dataset> = Vec::new();
-    for (cpu_core, cpu_data) in app.cpu_util.iter().enumerate() {
+    for (cpu_core, cpu_data) in cpu_points.iter().enumerate() {
         cpu_datasets.push(
             Dataset::default()
-                .name(format!("{}{}", "cpu", cpu_core.to_string()))
+                .name(format!("cpu{}", cpu_core))
                 .marker(symbols::Marker::Braille)
                 .style(Style::default().fg(color_gen.idx_to_color_persistant(cpu_core))) 
                 .data(cpu_data),
         );
     }
 
+    for (name, points) in &cpu_range {
+        cpu_datasets.push(
+            Dataset::default()
+                .name(*name)
+                .marker(symbols::Marker::Dot)
+                .style(Style::default().fg(Color::DarkGray))
+                .data(points),
+        );
+    }
+
     let network_datasets = vec![
         Dataset::default()
             .name("Tx")
             .marker(symbols::Marker::Braille)
             .style(Style::default().fg(Color::Cyan))
-            .data(&app.network_tx),
+            .data(&network_tx),
         Dataset::default()
             .name("Rx")
             .marker(symbols::Marker::Braille)
             .style(Style::default().fg(Color::Red))
-            .data(&app.network_rx),
+            .data(&network_rx),
     ];
 
-    draw_cpu_util(cpu_datasets, f, chunks[0]);
-    draw_network_util(network_datasets, f, chunks[1]);
+    let cpu_title = match &app.host {
+        Some(host) => format!(
+            "CPU - {} ({}, {} cores, {}) every {}ms",
+            host.hostname, host.cpu_model, host.core_count, host.os, app.sample_interval_ms
+        ),
+        None => format!("CPU every {}ms", app.sample_interval_ms),
+    };
+
+    draw_cpu_util(cpu_title, cpu_datasets, time_axis(view, bucket_secs), f, chunks[0]);
+    draw_network_util(network_datasets, time_axis(view, bucket_secs), f, chunks[1]);
     // TODO: pass (bounded) value here from app
 
-    draw_gpu_and_mem_util(get_gpu_ratio(app.gpu_power_draw.last(), app.gpu_power_limit), 
+    draw_gpu_and_mem_util(app.latest_gpu_ratio(),
         app.gpu_power_limit,
-        app.mem_util.last().unwrap_or(&(0.0, 0.0)).1,
+        app.latest_mem_ratio(),
         app.mem_total_bytes,
         f,
         chunks[2]);
 }
 
-fn get_gpu_ratio(gpu_power_draw: Option<&(f64, f64)>, max_gpu_power: f64) -> f64 {
-    if max_gpu_power == 0.0 { return 0.0; }
-
-    if let Some((x, active_draw)) = gpu_power_draw {
-        (active_draw/max_gpu_power).min(1.0).max(0.0)
-    } else {
-        0.0
-    }
-}
-
 fn draw_gpu_and_mem_util<B: Backend>(gpu_power_draw: f64, gpu_power_limit: f64, mem_util: f64, mem_total: u64, f: &mut Frame<B>, area: Rect) {
     let sublayout = Layout::default()
         .direction(Direction::Horizontal)
@@ -145,8 +422,33 @@ fn draw_gpu_power_draw<B: Backend>(gauge_ratio: f64, gpu_power_limit: f64, f: &m
     f.render_widget(gauge, area);
 }
 
+// e.g. -30s, -5m or -12h
+fn ago(secs: f64) -> String {
+    if secs >= 7_200.0 {
+        format!("-{}h", secs / 3_600.0)
+    } else if secs >= 120.0 {
+        format!("-{}m", secs / 60.0)
+    } else {
+        format!("-{}s", secs)
+    }
+}
+
+// Seconds before the newest sample, which sits at the right edge
+fn time_axis<'a>(view: View, bucket_secs: Option<f64>) -> Axis<'a> {
+    let title = match bucket_secs {
+        Some(bucket_secs) => format!("Time ({}, {}s buckets)", view.label(), bucket_secs),
+        None => format!("Time ({})", view.label()),
+    };
+    let span = view.span_secs();
+    Axis::default()
+        .title(title)
+        .style(Style::default().fg(Color::Gray))
+        .labels(vec![Span::raw(ago(span)), Span::raw(ago(span / 2.0)), Span::raw("now")])
+        .bounds([-span, 0.0])
+}
+
 // TODO: Should I dynamically size the y axis label & bounds?
-fn draw_network_util<B: Backend>(datasets: Vec<Dataset>, f: &mut Frame<B>, area: Rect) {
+fn draw_network_util<B: Backend>(datasets: Vec<Dataset>, x_axis: Axis, f: &mut Frame<B>, area: Rect) {
     let chart = Chart::new(datasets)
         .block(
             Block::default()
@@ -158,12 +460,7 @@ fn draw_network_util<B: Backend>(datasets: Vec<Dataset>, f: &mut Frame<B>, area:
                 ))
                 .borders(Borders::ALL),
         )
-        .x_axis(
-            Axis::default()
-                .title("Time")
-                .style(Style::default().fg(Color::Gray)) //TODO: add labels, dynamic bounds? or just hold static last X ticks
-                .bounds([0.0, 60.0]),
-        )
+        .x_axis(x_axis)
         .y_axis(
             Axis::default()
                 .title("kbps")
@@ -177,15 +474,10 @@ fn draw_network_util<B: Backend>(datasets: Vec<Dataset>, f: &mut Frame<B>, area:
     f.render_widget(chart, area);
 }
 
-fn draw_cpu_util<B: Backend>(datasets: Vec<Dataset>, f: &mut Frame<B>, area: Rect) {
+fn draw_cpu_util<B: Backend>(title: String, datasets: Vec<Dataset>, x_axis: Axis, f: &mut Frame<B>, area: Rect) {
     let chart = Chart::new(datasets)
-        .block(Block::default().title("CPU").borders(Borders::ALL))
-        .x_axis(
-            Axis::default()
-                .title("Time")
-                .style(Style::default().fg(Color::Gray))
-                .bounds([0.0, 60.0]), // TODO: Update x axis bounds
-        )
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .x_axis(x_axis)
         .y_axis(
             Axis::default()
                 .title("Util")
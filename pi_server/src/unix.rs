@@ -0,0 +1,109 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+// Parses a permission mode given in octal, e.g. 660
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("{} is not an octal permission mode like 660", mode)),
+    }
+}
+
+// Listens on a local socket that only users the mode lets write to it can connect to.
+// A socket left behind by a server that didn't exit cleanly is replaced, a live one is not.
+pub fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("a server is already listening on {}", path.display())))
+            }
+            // nothing listening, the server that made it is gone
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+            // e.g. not allowed to connect, which says nothing about whether a server is there
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::AccessList;
+    use crate::event_loop::{self, Listener};
+    use crate::ingest::{self, Backpressure};
+    use crate::shutdown::Shutdown;
+    use crate::{ClientMessage, ListenerPolicy};
+    use pitop_protocol::{Control, EnvelopeReader, EnvelopeWriter, MessageKind};
+    use std::time::{Duration, Instant};
+    use util_bundle::{HostInfo, UtilBundle};
+
+    #[test]
+    fn replaces_stale_socket_but_not_live_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.sock");
+
+        let listener = bind(&path, 0o600).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(bind(&path, 0o600).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        drop(listener);
+        bind(&path, 0o660).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+
+        fs::write(dir.path().join("notes"), "keep me").unwrap();
+        assert!(bind(&dir.path().join("notes"), 0o600).is_err());
+    }
+
+    #[test]
+    fn mode_must_be_octal() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert!(parse_mode("999").is_err());
+        assert!(parse_mode("7777").is_err());
+    }
+
+    #[test]
+    fn clients_connect_through_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.sock");
+        let listener = Listener::unix(bind(&path, 0o600).unwrap()).unwrap();
+        let (producer, consumer) = ingest::channel(64, Backpressure::DropNewest);
+        let shutdown = Shutdown::new();
+        let policy = ListenerPolicy { tls: None, psk: None, access: AccessList::default(), max_clients: None };
+        let stopper = shutdown.clone();
+        let server = std::thread::spawn(move || event_loop::serve(listener, policy, producer, &shutdown).unwrap());
+
+        let sock = UnixStream::connect(&path).unwrap();
+        let mut writer = EnvelopeWriter::new(sock.try_clone().unwrap());
+        let mut reader = EnvelopeReader::new(sock);
+        writer.send(MessageKind::Hello, &HostInfo { hostname: "local".to_string(), ..HostInfo::default() }).unwrap();
+        writer.send(MessageKind::Sample, &UtilBundle { cpu_usage: vec![42.0], ..Default::default() }).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut received = Vec::new();
+        while received.len() < 3 && Instant::now() < deadline {
+            received.extend(consumer.drain(Duration::from_millis(100)).into_iter().map(|(_, message)| message));
+        }
+        match &received[..] {
+            [ClientMessage::Connected(_, control), ClientMessage::Hello(host), ClientMessage::Sample(bundle)] => {
+                assert_eq!(host.hostname, "local");
+                assert_eq!(bundle.cpu_usage, vec![42.0]);
+                control.send(&Control::SampleNow).unwrap();
+            }
+            other => panic!("{} messages instead of connected, hello and sample", other.len()),
+        }
+        assert_eq!(reader.read_envelope().unwrap().unwrap().kind, MessageKind::Control);
+
+        stopper.request();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+#!/bin/sh
+# Regenerates the certificates the TLS tests use: a CA with a server and a client certificate,
+# and a second CA with a client certificate the first one doesn't know. Valid for 100 years.
+set -e
+cd "$(dirname "$0")"
+
+key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1.key"
+}
+
+ca() {
+    key "$1"
+    openssl req -x509 -new -key "$1.key" -out "$1.pem" -days 36500 -subj "/CN=pitop test $1" \
+        -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+}
+
+# name, signing ca, extensions
+leaf() {
+    key "$1"
+    openssl req -new -key "$1.key" -subj "/CN=pitop test $1" -out "$1.csr"
+    printf '%s\n' "$3" > "$1.ext"
+    openssl x509 -req -in "$1.csr" -CA "$2.pem" -CAkey "$2.key" -CAcreateserial -days 36500 \
+        -extfile "$1.ext" -out "$1.pem"
+    rm "$1.csr" "$1.ext"
+}
+
+ca ca
+ca other_ca
+leaf server ca "subjectAltName=DNS:localhost,IP:127.0.0.1
+extendedKeyUsage=serverAuth"
+leaf client ca "extendedKeyUsage=clientAuth"
+leaf other_client other_ca "extendedKeyUsage=clientAuth"
+rm -f ca.srl other_ca.srl ca.key other_ca.key
@@ -0,0 +1,214 @@
+// UDP transport: every datagram carries exactly one frame, so a lost datagram costs one message
+// instead of stalling the stream behind it. The envelope seq doubles as the datagram sequence
+// number, receivers use it to tell loss from reordering.
+
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::framing::{FrameDecoder, FRAME_HEADER_LEN, MAX_FRAME_LEN};
+use crate::Envelope;
+
+pub const MAX_DATAGRAM_LEN: usize = FRAME_HEADER_LEN + MAX_FRAME_LEN;
+
+// A seq this far behind the newest one is a restarted sender rather than a late datagram
+const REORDER_WINDOW: u64 = 1024;
+
+// Buffers writes and sends them as one datagram on flush, which is what write_frame ends with.
+// Reads hand out one received datagram at a time.
+pub struct DatagramStream {
+    socket: UdpSocket,
+    // None when the socket is connected
+    peer: Option<SocketAddr>,
+    outgoing: Vec<u8>,
+    incoming: Vec<u8>,
+    read_pos: usize,
+}
+
+impl DatagramStream {
+    pub fn connected(socket: UdpSocket) -> DatagramStream {
+        DatagramStream::new(socket, None)
+    }
+
+    // For answering a peer of an unconnected (listening) socket, write only
+    pub fn to(socket: UdpSocket, peer: SocketAddr) -> DatagramStream {
+        DatagramStream::new(socket, Some(peer))
+    }
+
+    fn new(socket: UdpSocket, peer: Option<SocketAddr>) -> DatagramStream {
+        DatagramStream { socket, peer, outgoing: Vec::new(), incoming: Vec::new(), read_pos: 0 }
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn try_clone(&self) -> io::Result<DatagramStream> {
+        Ok(DatagramStream::new(self.socket.try_clone()?, self.peer))
+    }
+}
+
+impl Read for DatagramStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read_pos == self.incoming.len() {
+            self.incoming.resize(MAX_DATAGRAM_LEN, 0);
+            let len = self.socket.recv(&mut self.incoming)?;
+            self.incoming.truncate(len);
+            self.read_pos = 0;
+        }
+        let n = buf.len().min(self.incoming.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.incoming[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for DatagramStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.outgoing.is_empty() {
+            return Ok(());
+        }
+        let sent = match self.peer {
+            Some(peer) => self.socket.send_to(&self.outgoing, peer),
+            None => self.socket.send(&self.outgoing),
+        };
+        // a failed datagram is gone either way, don't glue it onto the next one
+        self.outgoing.clear();
+        sent.map(|_| ())
+    }
+}
+
+// Decodes one received datagram, which must hold exactly one whole frame
+pub fn decode(datagram: &[u8]) -> io::Result<Envelope> {
+    let mut decoder = FrameDecoder::new();
+    decoder.push(datagram);
+    match decoder.next_frame()? {
+        Some(frame) if decoder.is_empty() => Envelope::from_bytes(&frame),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "datagram is not exactly one frame")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkStats {
+    pub received: u64,
+    pub lost: u64,
+    // arrived after a later datagram
+    pub reordered: u64,
+}
+
+impl LinkStats {
+    pub fn loss_rate(&self) -> f64 {
+        let expected = self.received + self.lost;
+        if expected == 0 {
+            return 0.0;
+        }
+        self.lost as f64 / expected as f64
+    }
+}
+
+// Follows the sequence numbers of one sender. A gap counts as lost until the missing datagrams
+// turn up late, then they count as reordered instead. Duplicates are ignored.
+#[derive(Default)]
+pub struct SeqTracker {
+    next: Option<u64>,
+    // skipped seqs within the reorder window that may still turn up
+    missing: BTreeSet<u64>,
+    stats: LinkStats,
+}
+
+impl SeqTracker {
+    pub fn new() -> SeqTracker {
+        SeqTracker::default()
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    // The sender started over with a new writer, e.g. after reconnecting from the same address
+    pub fn restart(&mut self) {
+        self.next = None;
+        self.missing.clear();
+    }
+
+    pub fn observe(&mut self, seq: u64) {
+        match self.next {
+            Some(next) if seq >= next => {
+                self.stats.lost += seq - next;
+                self.missing.extend(next.max(seq.saturating_sub(REORDER_WINDOW))..seq);
+                self.next = Some(seq + 1);
+                // too late to count as reordered now
+                self.missing = self.missing.split_off(&(seq + 1).saturating_sub(REORDER_WINDOW));
+            }
+            Some(next) if next - seq <= REORDER_WINDOW => {
+                if !self.missing.remove(&seq) {
+                    return;
+                }
+                self.stats.reordered += 1;
+                self.stats.lost = self.stats.lost.saturating_sub(1);
+            }
+            // first datagram, or the sender started counting again
+            _ => {
+                self.next = Some(seq + 1);
+                self.missing.clear();
+            }
+        }
+        self.stats.received += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EnvelopeWriter, MessageKind};
+
+    #[test]
+    fn one_frame_per_datagram() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+
+        let mut envelopes = EnvelopeWriter::new(DatagramStream::connected(sender));
+        envelopes.send(MessageKind::Heartbeat, &()).unwrap();
+        envelopes.send(MessageKind::Heartbeat, &()).unwrap();
+
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        for seq in 0..2 {
+            let len = receiver.recv(&mut buf).unwrap();
+            assert_eq!(decode(&buf[..len]).unwrap().seq, seq);
+        }
+        assert!(decode(&buf[..3]).is_err());
+    }
+
+    #[test]
+    fn tells_loss_from_reordering() {
+        let mut tracker = SeqTracker::new();
+        for seq in [0, 1, 3, 5, 4, 6] {
+            tracker.observe(seq);
+        }
+        // 2 never came, 4 came late
+        assert_eq!(tracker.stats(), LinkStats { received: 6, lost: 1, reordered: 1 });
+        assert!((tracker.stats().loss_rate() - 1.0 / 7.0).abs() < 1e-9);
+
+        // a restarted sender starts over instead of counting as thousands of late datagrams
+        tracker.observe(5000);
+        tracker.observe(0);
+        tracker.observe(1);
+        assert_eq!(tracker.stats().reordered, 1);
+        assert_eq!(tracker.stats().lost, 1 + 4993);
+    }
+
+    #[test]
+    fn duplicates_are_ignored() {
+        let mut tracker = SeqTracker::new();
+        for seq in [0, 1, 1, 3, 2, 2, 0, 3, 4] {
+            tracker.observe(seq);
+        }
+        assert_eq!(tracker.stats(), LinkStats { received: 5, lost: 0, reordered: 1 });
+    }
+}
@@ -0,0 +1,125 @@
+// Zero-config discovery: a server periodically announces itself in a Beacon datagram, sent to
+// the LAN broadcast address or a multicast group, and clients started without an address listen
+// for one. The server's address is wherever the beacon came from, so a Pi whose DHCP lease
+// changed is found again on the next reconnect.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::datagram::{self, DatagramStream, MAX_DATAGRAM_LEN};
+use crate::{EnvelopeWriter, MessageKind};
+
+pub const DISCOVERY_PORT: u16 = 7879;
+// organization-local scope, for networks that filter broadcasts
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 70, 84);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Beacon {
+    // lets clients pick one server when several share a network
+    pub name: String,
+    pub port: u16,
+    // "tcp" or "udp"
+    pub transport: String,
+    pub tls: bool,
+    pub psk: bool,
+}
+
+pub struct BeaconSender {
+    envelopes: EnvelopeWriter<DatagramStream>,
+}
+
+impl BeaconSender {
+    // target is a broadcast address like 255.255.255.255:7879 or a multicast group
+    pub fn new(target: SocketAddr) -> io::Result<BeaconSender> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.set_broadcast(true)?;
+        Ok(BeaconSender { envelopes: EnvelopeWriter::new(DatagramStream::to(socket, target)) })
+    }
+
+    pub fn send(&mut self, beacon: &Beacon) -> io::Result<()> {
+        self.envelopes.send(MessageKind::Beacon, beacon)
+    }
+}
+
+pub struct BeaconListener {
+    socket: UdpSocket,
+}
+
+impl BeaconListener {
+    // Hears broadcasts on the port and, where the network allows it, the discovery group
+    pub fn bind(port: u16) -> io::Result<BeaconListener> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        if let Err(e) = socket.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED) {
+            eprintln!("Not listening for multicast beacons: {}", e);
+        }
+        Ok(BeaconListener { socket })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // Waits up to timeout for a beacon the filter accepts. Returns the server's address with it.
+    pub fn wait<F: Fn(&Beacon) -> bool>(&self, timeout: Duration, filter: F) -> io::Result<Option<(SocketAddr, Beacon)>> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+
+        // beacons queued up since the last call may point at where a server used to be
+        self.socket.set_nonblocking(true)?;
+        while self.socket.recv_from(&mut buf).is_ok() {}
+        self.socket.set_nonblocking(false)?;
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            // anything else on the port, or a beacon from an incompatible server, is ignored
+            let Ok(envelope) = datagram::decode(&buf[..len]) else { continue };
+            if envelope.kind != MessageKind::Beacon {
+                continue;
+            }
+            let Ok(beacon) = envelope.payload::<Beacon>() else { continue };
+            if filter(&beacon) {
+                return Ok(Some((SocketAddr::new(from.ip(), beacon.port), beacon)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_finds_the_named_server() {
+        let listener = BeaconListener::bind(0).unwrap();
+        let target = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listener.local_addr().unwrap().port());
+        let mut sender = BeaconSender::new(target).unwrap();
+        // sent after wait() has started, it skips beacons that queued up before
+        let beacons = std::thread::spawn(move || {
+            for name in ["lab-pi", "desk-pi"].iter().cycle().take(20) {
+                std::thread::sleep(Duration::from_millis(20));
+                let beacon = Beacon { name: name.to_string(), port: 7878, transport: "tcp".to_string(), ..Beacon::default() };
+                sender.send(&beacon).unwrap();
+            }
+        });
+
+        let (addr, beacon) = listener.wait(Duration::from_secs(2), |b| b.name == "desk-pi").unwrap().unwrap();
+        assert_eq!(beacon.name, "desk-pi");
+        assert_eq!(addr, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 7878));
+        beacons.join().unwrap();
+        assert!(listener.wait(Duration::from_millis(50), |_| true).unwrap().is_none());
+    }
+}
@@ -0,0 +1,297 @@
+// Purpose: Length-prefixed framing shared by the client and server
+//
+// Every frame on the wire is a 4 byte big-endian payload length followed by the payload itself.
+// Unlike the old newline scan this survives reads that split a frame, reads that carry several
+// frames, and payloads that happen to contain a newline.
+
+use std::io::{self, Read, Write};
+
+pub const FRAME_HEADER_LEN: usize = 4;
+pub const MAX_FRAME_LEN: usize = 65536;
+
+const READ_CHUNK_SIZE: usize = 1024;
+
+fn oversized_frame_error(len: usize, max_len: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("frame of {} bytes exceeds limit of {} bytes", len, max_len),
+    )
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(oversized_frame_error(payload.len(), MAX_FRAME_LEN));
+    }
+
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+// How to spot a real frame on a byte stream with no integrity of its own, like a serial line.
+// Frames are the same as anywhere else, a header only counts if its payload starts with prefix
+// and the whole payload passes check. Anything else is line noise.
+#[derive(Clone, Copy)]
+pub struct Resync {
+    pub prefix: &'static [u8],
+    pub check: fn(&[u8]) -> bool,
+}
+
+// Incremental decoder: feed it whatever bytes arrive and pull complete frames back out
+pub struct FrameDecoder {
+    pending: Vec<u8>,
+    max_frame_len: usize,
+    // bytes of an oversized frame we still have to throw away before the next header
+    discarding: usize,
+    resync: Option<Resync>,
+    // noise thrown away while resyncing
+    skipped: u64,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::with_max_frame_len(MAX_FRAME_LEN)
+    }
+
+    pub fn with_max_frame_len(max_frame_len: usize) -> FrameDecoder {
+        FrameDecoder {
+            pending: Vec::new(),
+            max_frame_len,
+            discarding: 0,
+            resync: None,
+            skipped: 0,
+        }
+    }
+
+    // Instead of trusting every header, slides forward a byte at a time until one checks out
+    pub fn resyncing(resync: Resync) -> FrameDecoder {
+        FrameDecoder { resync: Some(resync), ..FrameDecoder::new() }
+    }
+
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        let skipped = self.discarding.min(bytes.len());
+        self.discarding -= skipped;
+        self.pending.extend_from_slice(&bytes[skipped..]);
+    }
+
+    // True when no partial frame is buffered, i.e. the stream is sitting on a frame boundary
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.discarding == 0
+    }
+
+    // Returns the next complete frame, or None if more bytes are needed.
+    // An oversized frame yields an InvalidData error once; its payload is then skipped so the
+    // decoder stays aligned and later frames decode normally.
+    pub fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Some(resync) = self.resync {
+            return Ok(self.next_checked_frame(resync));
+        }
+        if self.discarding > 0 || self.pending.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut header = [0; FRAME_HEADER_LEN];
+        header.copy_from_slice(&self.pending[..FRAME_HEADER_LEN]);
+        let frame_len = u32::from_be_bytes(header) as usize;
+
+        if frame_len > self.max_frame_len {
+            let available = self.pending.len() - FRAME_HEADER_LEN;
+            let skipped = frame_len.min(available);
+            self.pending.drain(..FRAME_HEADER_LEN + skipped);
+            self.discarding = frame_len - skipped;
+            return Err(oversized_frame_error(frame_len, self.max_frame_len));
+        }
+
+        if self.pending.len() < FRAME_HEADER_LEN + frame_len {
+            return Ok(None);
+        }
+
+        let frame = self.pending[FRAME_HEADER_LEN..FRAME_HEADER_LEN + frame_len].to_vec();
+        self.pending.drain(..FRAME_HEADER_LEN + frame_len);
+        Ok(Some(frame))
+    }
+
+    // The prefix is looked at as soon as it arrives, so a noise "header" claiming a huge frame
+    // doesn't stall the stream until that many bytes have come in
+    fn next_checked_frame(&mut self, resync: Resync) -> Option<Vec<u8>> {
+        loop {
+            if self.pending.len() < FRAME_HEADER_LEN {
+                return None;
+            }
+            let mut header = [0; FRAME_HEADER_LEN];
+            header.copy_from_slice(&self.pending[..FRAME_HEADER_LEN]);
+            let frame_len = u32::from_be_bytes(header) as usize;
+
+            let plausible = frame_len <= self.max_frame_len && frame_len >= resync.prefix.len();
+            if plausible {
+                let prefix_end = FRAME_HEADER_LEN + resync.prefix.len();
+                if self.pending.len() < prefix_end {
+                    return None;
+                }
+                if self.pending[FRAME_HEADER_LEN..prefix_end] == *resync.prefix {
+                    if self.pending.len() < FRAME_HEADER_LEN + frame_len {
+                        return None;
+                    }
+                    let frame = &self.pending[FRAME_HEADER_LEN..FRAME_HEADER_LEN + frame_len];
+                    if (resync.check)(frame) {
+                        let frame = frame.to_vec();
+                        self.pending.drain(..FRAME_HEADER_LEN + frame_len);
+                        return Some(frame);
+                    }
+                }
+            }
+
+            // not a frame boundary after all, the next one may start a byte later
+            self.pending.drain(..1);
+            self.skipped += 1;
+        }
+    }
+}
+
+// Blocking frame reader over any byte stream (TcpStream, stdin, ...)
+pub struct FrameReader<R: Read> {
+    inner: R,
+    decoder: FrameDecoder,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> FrameReader<R> {
+        FrameReader::with_decoder(inner, FrameDecoder::new())
+    }
+
+    pub fn with_decoder(inner: R, decoder: FrameDecoder) -> FrameReader<R> {
+        FrameReader { inner, decoder }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    // Ok(None) means the peer closed the stream cleanly on a frame boundary
+    pub fn read_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = [0; READ_CHUNK_SIZE];
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(Some(frame));
+            }
+
+            let bytes_read = self.inner.read(&mut buf)?;
+            if bytes_read == 0 {
+                return if self.decoder.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream closed mid-frame"))
+                };
+            }
+            self.decoder.push(&buf[..bytes_read]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(payloads: &[&[u8]]) -> Vec<u8> {
+        let mut wire = Vec::new();
+        for payload in payloads {
+            write_frame(&mut wire, payload).unwrap();
+        }
+        wire
+    }
+
+    #[test]
+    fn decodes_frame_split_across_reads() {
+        let wire = framed(&[b"hello\nworld"]);
+        let mut decoder = FrameDecoder::new();
+
+        for byte in &wire[..wire.len() - 1] {
+            decoder.push(&[*byte]);
+            assert!(decoder.next_frame().unwrap().is_none());
+        }
+        decoder.push(&wire[wire.len() - 1..]);
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"hello\nworld");
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn decodes_several_frames_from_one_read() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&framed(&[b"one", b"", b"three"]));
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"one");
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"");
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"three");
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn skips_oversized_frame_and_resumes() {
+        let mut decoder = FrameDecoder::with_max_frame_len(4);
+        let wire = framed(&[b"too long", b"ok"]);
+
+        // oversized payload arrives in pieces after the header
+        decoder.push(&wire[..6]);
+        let err = decoder.next_frame().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(decoder.next_frame().unwrap().is_none());
+
+        decoder.push(&wire[6..]);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"ok");
+    }
+
+    #[test]
+    fn resyncs_after_line_noise() {
+        let resync = Resync { prefix: b"<", check: |frame| frame.ends_with(b">") };
+        let mut decoder = FrameDecoder::resyncing(resync);
+
+        let mut wire = vec![0xff, 0x00, 0x00];
+        wire.extend(framed(&[b"<one>"]));
+        // a frame with a flipped byte, then noise that looks like a huge header
+        let mut damaged = framed(&[b"<two>"]);
+        damaged[8] ^= 0x20;
+        wire.extend(damaged);
+        wire.extend([0x00, 0x00, 0xff, 0xff, b'x']);
+        wire.extend(framed(&[b"<three>"]));
+
+        decoder.push(&wire);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"<one>");
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"<three>");
+        assert!(decoder.next_frame().unwrap().is_none());
+        assert!(decoder.is_empty());
+        assert_eq!(decoder.skipped(), 3 + 9 + 5);
+    }
+
+    #[test]
+    fn write_rejects_oversized_payload() {
+        let payload = vec![0; MAX_FRAME_LEN + 1];
+        let mut wire = Vec::new();
+        assert!(write_frame(&mut wire, &payload).is_err());
+        assert!(wire.is_empty());
+    }
+
+    #[test]
+    fn reader_reports_clean_and_truncated_eof() {
+        let wire = framed(&[b"a", b"b"]);
+        let mut reader = FrameReader::new(&wire[..]);
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"a");
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"b");
+        assert!(reader.read_frame().unwrap().is_none());
+
+        let mut truncated = FrameReader::new(&wire[..wire.len() - 1]);
+        truncated.read_frame().unwrap();
+        let err = truncated.read_frame().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
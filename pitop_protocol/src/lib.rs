@@ -0,0 +1,263 @@
+// Purpose: Wire protocol shared by the PiTop client and server
+//
+// Every frame (see framing) carries one JSON encoded Envelope. Compatibility rules:
+// 1. Envelopes with the same major version are always accepted, whatever their minor version.
+// 2. Minor bumps are additive only: new optional fields (#[serde(default)]) or new message kinds.
+//    Fields are never renamed, retyped or removed within a major version.
+// 3. Unknown fields are ignored, and unknown message kinds decode as MessageKind::Unknown so
+//    receivers can skip them instead of dropping the connection.
+// 4. Anything else is a major bump, and receivers reject envelopes from a different major.
+
+pub mod auth;
+pub mod datagram;
+pub mod discovery;
+pub mod framing;
+pub mod serial;
+pub mod tls;
+
+use std::io::{self, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::framing::{write_frame, FrameDecoder, FrameReader, Resync};
+
+use util_bundle::MetricGroup;
+
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion { major: 1, minor: 7 };
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl ProtocolVersion {
+    pub fn is_compatible_with(&self, other: &ProtocolVersion) -> bool {
+        self.major == other.major
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    // payload is a util_bundle::HostInfo, sent once right after connecting (since 1.1)
+    Hello,
+    // payload is a util_bundle::UtilBundle, stamped with sampled_at_ms since 1.2 so spooled samples
+    // replayed after a reconnect keep their original time
+    Sample,
+    // no payload, sent when nothing else went out for a while so the server can tell a quiet
+    // client from a dead one (since 1.3)
+    Heartbeat,
+    // server to client, payload is a Control (since 1.4)
+    Control,
+    // pre-shared key handshake, see auth (since 1.5)
+    AuthChallenge,
+    AuthResponse,
+    // server announcement on the discovery port, payload is a discovery::Beacon (since 1.6)
+    Beacon,
+    // either direction, payload is a Goodbye, sent right before hanging up on purpose so the peer
+    // can tell a shutdown from a lost connection (since 1.7)
+    Goodbye,
+    // sent by a newer peer, receivers should skip it
+    #[serde(other)]
+    Unknown,
+}
+
+// Bounds on SetSampleInterval, the server never asks for more and a client never does more
+pub const MIN_SAMPLE_INTERVAL_MILLIS: u64 = 50;
+pub const MAX_SAMPLE_INTERVAL_MILLIS: u64 = 60_000;
+
+// Requests the server sends down to a client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Control {
+    SetSampleInterval { millis: u64 },
+    SetMetricGroup { group: MetricGroup, enabled: bool },
+    // take and send a sample right away instead of waiting for the next interval
+    SampleNow,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Goodbye {
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    pub version: ProtocolVersion,
+    pub kind: MessageKind,
+    pub seq: u64,
+    // sender wall clock, milliseconds since the unix epoch
+    pub sent_at_ms: u64,
+    pub payload: serde_json::Value,
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Envelope {
+    pub fn new<T: Serialize>(kind: MessageKind, seq: u64, payload: &T) -> serde_json::Result<Envelope> {
+        Ok(Envelope {
+            version: PROTOCOL_VERSION,
+            kind,
+            seq,
+            sent_at_ms: now_millis(),
+            payload: serde_json::to_value(payload)?,
+        })
+    }
+
+    pub fn payload<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        T::deserialize(&self.payload)
+    }
+
+    pub fn to_bytes(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+
+    // Fails with InvalidData for garbage and Unsupported for an incompatible protocol major
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Envelope> {
+        let envelope: Envelope = serde_json::from_slice(bytes)?;
+        if !PROTOCOL_VERSION.is_compatible_with(&envelope.version) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "peer speaks protocol {}.{}, expected {}.x",
+                    envelope.version.major, envelope.version.minor, PROTOCOL_VERSION.major
+                ),
+            ));
+        }
+        Ok(envelope)
+    }
+}
+
+// Stamps outgoing envelopes with increasing sequence numbers
+pub struct EnvelopeWriter<W: Write> {
+    inner: W,
+    next_seq: u64,
+}
+
+impl<W: Write> EnvelopeWriter<W> {
+    pub fn new(inner: W) -> EnvelopeWriter<W> {
+        EnvelopeWriter { inner, next_seq: 0 }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn send<T: Serialize>(&mut self, kind: MessageKind, payload: &T) -> io::Result<()> {
+        let envelope = Envelope::new(kind, self.next_seq, payload)?;
+        write_frame(&mut self.inner, &envelope.to_bytes()?)?;
+        self.next_seq += 1;
+        Ok(())
+    }
+}
+
+pub struct EnvelopeReader<R: Read> {
+    frames: FrameReader<R>,
+}
+
+impl<R: Read> EnvelopeReader<R> {
+    pub fn new(inner: R) -> EnvelopeReader<R> {
+        EnvelopeReader { frames: FrameReader::new(inner) }
+    }
+
+    // For byte streams that can corrupt or lose bytes (serial lines): only frames that decode as
+    // envelopes count, noise in between is skipped until the next frame boundary
+    pub fn resyncing(inner: R) -> EnvelopeReader<R> {
+        let resync = Resync {
+            // serde writes fields in declaration order, so every envelope starts like this
+            prefix: b"{\"version\":",
+            check: |frame| serde_json::from_slice::<Envelope>(frame).is_ok(),
+        };
+        EnvelopeReader { frames: FrameReader::with_decoder(inner, FrameDecoder::resyncing(resync)) }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.frames.get_ref()
+    }
+
+    // Ok(None) on clean end of stream. InvalidData errors only spoil the current frame,
+    // so callers may log them and keep reading.
+    pub fn read_envelope(&mut self) -> io::Result<Option<Envelope>> {
+        match self.frames.read_frame()? {
+            Some(frame) => Envelope::from_bytes(&frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+    struct SampleV1 {
+        cpu: f32,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+    #[serde(default)]
+    struct SampleV2 {
+        cpu: f32,
+        fan_rpm: u32,
+    }
+
+    #[test]
+    fn round_trips_with_increasing_seq() {
+        let mut writer = EnvelopeWriter::new(Vec::new());
+        writer.send(MessageKind::Sample, &SampleV1 { cpu: 1.0 }).unwrap();
+        writer.send(MessageKind::Sample, &SampleV1 { cpu: 2.0 }).unwrap();
+
+        let mut reader = EnvelopeReader::new(&writer.get_mut()[..]);
+        let first = reader.read_envelope().unwrap().unwrap();
+        let second = reader.read_envelope().unwrap().unwrap();
+        assert_eq!((first.seq, second.seq), (0, 1));
+        assert_eq!(first.kind, MessageKind::Sample);
+        assert_eq!(second.payload::<SampleV1>().unwrap(), SampleV1 { cpu: 2.0 });
+        assert!(reader.read_envelope().unwrap().is_none());
+    }
+
+    #[test]
+    fn payload_fields_evolve_in_both_directions() {
+        let old = Envelope::new(MessageKind::Sample, 0, &SampleV1 { cpu: 3.0 }).unwrap();
+        assert_eq!(old.payload::<SampleV2>().unwrap(), SampleV2 { cpu: 3.0, fan_rpm: 0 });
+
+        let new = Envelope::new(MessageKind::Sample, 0, &SampleV2 { cpu: 4.0, fan_rpm: 900 }).unwrap();
+        assert_eq!(new.payload::<SampleV1>().unwrap(), SampleV1 { cpu: 4.0 });
+    }
+
+    #[test]
+    fn newer_minor_and_unknown_kind_are_accepted() {
+        let json = br#"{"version":{"major":1,"minor":7},"kind":"telepathy","seq":9,
+            "sent_at_ms":0,"payload":null,"extra":true}"#;
+        let envelope = Envelope::from_bytes(json).unwrap();
+        assert_eq!(envelope.kind, MessageKind::Unknown);
+        assert_eq!(envelope.seq, 9);
+    }
+
+    #[test]
+    fn control_round_trips_and_tolerates_unknown_requests() {
+        let control = Control::SetMetricGroup { group: MetricGroup::Gpu, enabled: false };
+        let envelope = Envelope::new(MessageKind::Control, 0, &control).unwrap();
+        assert_eq!(envelope.payload::<Control>().unwrap(), control);
+
+        let unknown: Control = serde_json::from_str(r#"{"type":"reboot"}"#).unwrap();
+        assert_eq!(unknown, Control::Unknown);
+    }
+
+    #[test]
+    fn other_major_is_rejected() {
+        let json = br#"{"version":{"major":2,"minor":0},"kind":"sample","seq":0,"sent_at_ms":0,"payload":null}"#;
+        let err = Envelope::from_bytes(json).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
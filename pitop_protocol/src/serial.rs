@@ -0,0 +1,317 @@
+// Serial transport, for a Pi cabled to the PC over a UART or USB serial gadget. Frames are the
+// same as over TCP, but the line has no checksums or retransmission, so readers should use
+// EnvelopeReader::resyncing to skip noise and find the next frame boundary.
+//
+// The line is always 8N1, raw, without flow control. Only the baud rate is configurable.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+pub const DEFAULT_BAUD: u32 = 115_200;
+
+pub struct SerialPort {
+    file: File,
+    // per handle, clones start out blocking
+    read_timeout: Option<Duration>,
+}
+
+impl SerialPort {
+    pub fn open(path: &Path, baud: u32) -> io::Result<SerialPort> {
+        let file = sys::open(path, baud)?;
+        Ok(SerialPort { file, read_timeout: None })
+    }
+
+    pub fn try_clone(&self) -> io::Result<SerialPort> {
+        Ok(SerialPort { file: self.file.try_clone()?, read_timeout: None })
+    }
+
+    // Reads that see nothing for this long fail with TimedOut
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        sys::set_read_timeout(&self.file, timeout)?;
+        self.read_timeout = timeout;
+        Ok(())
+    }
+}
+
+impl Read for SerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        sys::read(&mut self.file, self.read_timeout, buf)
+    }
+}
+
+impl Write for SerialPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn check(ret: libc::c_int) -> io::Result<()> {
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn speed(baud: u32) -> io::Result<libc::speed_t> {
+        Ok(match baud {
+            1200 => libc::B1200,
+            2400 => libc::B2400,
+            4800 => libc::B4800,
+            9600 => libc::B9600,
+            19200 => libc::B19200,
+            38400 => libc::B38400,
+            57600 => libc::B57600,
+            115200 => libc::B115200,
+            230400 => libc::B230400,
+            460800 => libc::B460800,
+            921600 => libc::B921600,
+            1000000 => libc::B1000000,
+            1500000 => libc::B1500000,
+            2000000 => libc::B2000000,
+            3000000 => libc::B3000000,
+            4000000 => libc::B4000000,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported baud rate {}", baud))),
+        })
+    }
+
+    // the BSDs and macOS take the rate itself
+    #[cfg(not(target_os = "linux"))]
+    fn speed(baud: u32) -> io::Result<libc::speed_t> {
+        Ok(baud as libc::speed_t)
+    }
+
+    pub fn open(path: &Path, baud: u32) -> io::Result<File> {
+        let speed = speed(baud)?;
+        // the line must not become our controlling terminal
+        let file = OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open(path)?;
+        let fd = file.as_raw_fd();
+
+        unsafe {
+            let mut tio: libc::termios = std::mem::zeroed();
+            check(libc::tcgetattr(fd, &mut tio))?;
+            libc::cfmakeraw(&mut tio);
+            tio.c_cflag |= libc::CLOCAL | libc::CREAD;
+            tio.c_cflag &= !(libc::CSTOPB | libc::CRTSCTS);
+            tio.c_cc[libc::VMIN] = 1;
+            tio.c_cc[libc::VTIME] = 0;
+            check(libc::cfsetispeed(&mut tio, speed))?;
+            check(libc::cfsetospeed(&mut tio, speed))?;
+            check(libc::tcsetattr(fd, libc::TCSANOW, &tio))?;
+            // whatever sat in the buffers belongs to an earlier session
+            check(libc::tcflush(fd, libc::TCIOFLUSH))?;
+        }
+        Ok(file)
+    }
+
+    // timeouts are handled by polling before each read
+    pub fn set_read_timeout(_file: &File, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn read(file: &mut File, timeout: Option<Duration>, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(timeout) = timeout {
+            wait_readable(file, timeout)?;
+        }
+        file.read(buf)
+    }
+
+    fn wait_readable(file: &File, timeout: Duration) -> io::Result<()> {
+        let mut fds = libc::pollfd { fd: file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        loop {
+            match unsafe { libc::poll(&mut fds, 1, millis) } {
+                0 => return Err(io::ErrorKind::TimedOut.into()),
+                n if n > 0 => return Ok(()),
+                _ => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::ffi::c_void;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read};
+    use std::os::windows::io::AsRawHandle;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    const MAXDWORD: u32 = u32::MAX;
+    // fBinary, fDtrControl = DTR_CONTROL_ENABLE, fRtsControl = RTS_CONTROL_ENABLE
+    const DCB_FLAGS: u32 = 0x0001 | 0x0010 | 0x1000;
+
+    #[repr(C)]
+    struct Dcb {
+        dcb_length: u32,
+        baud_rate: u32,
+        flags: u32,
+        reserved: u16,
+        xon_lim: u16,
+        xoff_lim: u16,
+        byte_size: u8,
+        parity: u8,
+        stop_bits: u8,
+        xon_char: i8,
+        xoff_char: i8,
+        error_char: i8,
+        eof_char: i8,
+        evt_char: i8,
+        reserved1: u16,
+    }
+
+    #[repr(C)]
+    struct CommTimeouts {
+        read_interval_timeout: u32,
+        read_total_timeout_multiplier: u32,
+        read_total_timeout_constant: u32,
+        write_total_timeout_multiplier: u32,
+        write_total_timeout_constant: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCommState(file: *mut c_void, dcb: *mut Dcb) -> i32;
+        fn SetCommState(file: *mut c_void, dcb: *const Dcb) -> i32;
+        fn SetCommTimeouts(file: *mut c_void, timeouts: *const CommTimeouts) -> i32;
+        fn PurgeComm(file: *mut c_void, flags: u32) -> i32;
+    }
+
+    fn check(ret: i32) -> io::Result<()> {
+        if ret == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // COM10 and up only open through the device namespace, COM1 works either way
+    fn device_path(path: &Path) -> PathBuf {
+        if path.to_string_lossy().starts_with(r"\\") {
+            return path.to_path_buf();
+        }
+        Path::new(r"\\.\").join(path)
+    }
+
+    pub fn open(path: &Path, baud: u32) -> io::Result<File> {
+        let file = OpenOptions::new().read(true).write(true).open(device_path(path))?;
+        let handle = file.as_raw_handle();
+
+        unsafe {
+            let mut dcb: Dcb = std::mem::zeroed();
+            dcb.dcb_length = std::mem::size_of::<Dcb>() as u32;
+            check(GetCommState(handle, &mut dcb))?;
+            dcb.baud_rate = baud;
+            dcb.flags = DCB_FLAGS;
+            dcb.byte_size = 8;
+            // NOPARITY, ONESTOPBIT
+            dcb.parity = 0;
+            dcb.stop_bits = 0;
+            check(SetCommState(handle, &dcb))?;
+            // PURGE_TXCLEAR | PURGE_RXCLEAR
+            check(PurgeComm(handle, 0x0004 | 0x0008))?;
+        }
+        set_read_timeout(&file, None)?;
+        Ok(file)
+    }
+
+    // A read returns as soon as any bytes are there, or with nothing once the timeout passes
+    pub fn set_read_timeout(file: &File, timeout: Option<Duration>) -> io::Result<()> {
+        let constant = match timeout {
+            Some(timeout) => timeout.as_millis().clamp(1, (MAXDWORD - 1) as u128) as u32,
+            None => MAXDWORD - 1,
+        };
+        let timeouts = CommTimeouts {
+            read_interval_timeout: MAXDWORD,
+            read_total_timeout_multiplier: MAXDWORD,
+            read_total_timeout_constant: constant,
+            write_total_timeout_multiplier: 0,
+            write_total_timeout_constant: 0,
+        };
+        check(unsafe { SetCommTimeouts(file.as_raw_handle(), &timeouts) })
+    }
+
+    // the comm timeouts do the waiting, a read that comes back empty ran out of time
+    pub fn read(file: &mut File, timeout: Option<Duration>, buf: &mut [u8]) -> io::Result<usize> {
+        match file.read(buf)? {
+            0 if timeout.is_some() && !buf.is_empty() => Err(io::ErrorKind::TimedOut.into()),
+            n => Ok(n),
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::{EnvelopeReader, EnvelopeWriter, MessageKind};
+    use std::ffi::CStr;
+    use std::os::unix::io::FromRawFd;
+
+    // The master end stands in for the far side of the cable
+    fn pty() -> (File, SerialPort) {
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(master >= 0);
+            assert_eq!(libc::grantpt(master), 0);
+            assert_eq!(libc::unlockpt(master), 0);
+            let mut name = [0 as libc::c_char; 128];
+            assert_eq!(libc::ptsname_r(master, name.as_mut_ptr(), name.len()), 0);
+            let path = CStr::from_ptr(name.as_ptr()).to_str().unwrap().to_string();
+            (File::from_raw_fd(master), SerialPort::open(Path::new(&path), DEFAULT_BAUD).unwrap())
+        }
+    }
+
+    #[test]
+    fn reads_through_line_noise_over_a_pty() {
+        let (mut far_end, port) = pty();
+
+        let mut envelopes = EnvelopeWriter::new(Vec::new());
+        envelopes.send(MessageKind::Heartbeat, &()).unwrap();
+        let first_len = envelopes.get_mut().len();
+        envelopes.send(MessageKind::Heartbeat, &()).unwrap();
+        envelopes.send(MessageKind::Heartbeat, &()).unwrap();
+        let mut wire = b"\x00\x13garbage".to_vec();
+        wire.extend_from_slice(envelopes.get_mut());
+        // corrupt the middle envelope
+        wire[9 + first_len + 30] = b'#';
+        far_end.write_all(&wire).unwrap();
+
+        let mut incoming = EnvelopeReader::resyncing(port.try_clone().unwrap());
+        assert_eq!(incoming.read_envelope().unwrap().unwrap().seq, 0);
+        assert_eq!(incoming.read_envelope().unwrap().unwrap().seq, 2);
+
+        // and the other way
+        let mut outgoing = EnvelopeWriter::new(port);
+        outgoing.send(MessageKind::Heartbeat, &()).unwrap();
+        let mut far_envelopes = EnvelopeReader::new(far_end);
+        assert_eq!(far_envelopes.read_envelope().unwrap().unwrap().kind, MessageKind::Heartbeat);
+    }
+
+    #[test]
+    fn read_timeout_expires() {
+        let (_far_end, mut port) = pty();
+        port.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let err = port.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,203 @@
+// Optional TLS for the TCP transport, configured from local PEM files
+//
+// TlsStream is the client's end, wrapping a connected TcpStream once the handshake is done. Clones
+// share the TLS session, so one thread can block reading while another writes (samples one way,
+// control messages the other). The server drives a ServerConnection from its event loop instead.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, ClientConnection, Connection, PrivateKey, RootCertStore, ServerName};
+
+pub use rustls::{ClientConfig, ServerConfig, ServerConnection};
+
+const READ_CHUNK_LEN: usize = 4096;
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn open_pem(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open_pem(path)?)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: no certificates found", path.display()),
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = open_pem(path)?;
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: no private key found", path.display()),
+    ))
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
+
+// With a client CA the server refuses clients that don't present a certificate signed by it
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(ca) => builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(ca)?).boxed()),
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?).map_err(tls_error)?;
+    Ok(Arc::new(config))
+}
+
+// The client certificate is only needed when the server requires one
+pub fn client_config(ca: &Path, cert_and_key: Option<(&Path, &Path)>) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(load_roots(ca)?);
+    let config = match cert_and_key {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?).map_err(tls_error)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+pub struct TlsStream {
+    session: Arc<Mutex<Connection>>,
+    sock: TcpStream,
+}
+
+impl TlsStream {
+    // server_name is checked against the server certificate, a hostname or an IP address
+    pub fn connect(config: Arc<ClientConfig>, server_name: &str, sock: TcpStream) -> io::Result<TlsStream> {
+        let name = ServerName::try_from(server_name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid TLS server name {}", server_name)))?;
+        let session = ClientConnection::new(config, name).map_err(tls_error)?;
+        TlsStream::handshake(Connection::Client(session), sock)
+    }
+
+    // Blocks until the handshake is done, so a bad certificate fails here and not on first use
+    fn handshake(mut session: Connection, mut sock: TcpStream) -> io::Result<TlsStream> {
+        while session.is_handshaking() {
+            session.complete_io(&mut sock)?;
+        }
+        Ok(TlsStream { session: Arc::new(Mutex::new(session)), sock })
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    pub fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream { session: Arc::clone(&self.session), sock: self.sock.try_clone()? })
+    }
+
+    // Says goodbye with close_notify so the peer sees a clean end of stream
+    pub fn shutdown(&self) -> io::Result<()> {
+        if let Ok(mut session) = self.lock() {
+            session.send_close_notify();
+            let _ = self.write_pending(&mut session);
+        }
+        self.sock.shutdown(Shutdown::Both)
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, Connection>> {
+        self.session.lock().map_err(|_| io::Error::other("TLS session poisoned"))
+    }
+
+    fn write_pending(&self, session: &mut Connection) -> io::Result<()> {
+        let mut sock = &self.sock;
+        while session.wants_write() {
+            session.write_tls(&mut sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = [0u8; READ_CHUNK_LEN];
+        loop {
+            match self.lock()?.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            // the socket is read without holding the session, writers on clones must not wait on it
+            let n = (&self.sock).read(&mut chunk)?;
+            let mut session = self.lock()?;
+            let mut received = &chunk[..n];
+            // an empty read_tls records end of stream, the reader then reports how it ended
+            loop {
+                session.read_tls(&mut received)?;
+                if received.is_empty() {
+                    break;
+                }
+            }
+            // the session is dead after a TLS error, unlike a bad frame it can't be skipped
+            session
+                .process_new_packets()
+                .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e))?;
+            // alerts and key updates
+            self.write_pending(&mut session)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.lock()?;
+        let n = session.writer().write(buf)?;
+        self.write_pending(&mut session)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.lock()?;
+        session.writer().flush()?;
+        self.write_pending(&mut session)?;
+        (&self.sock).flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_files_name_the_path() {
+        let err = server_config(Path::new("/nonexistent/server.pem"), Path::new("/nonexistent/server.key"), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("/nonexistent/server.pem"));
+    }
+
+    #[test]
+    fn pem_without_certificates_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.pem");
+        std::fs::write(&path, "not a certificate\n").unwrap();
+        let err = client_config(&path, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
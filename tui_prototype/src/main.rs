@@ -117,7 +117,7 @@ fn rand_color() -> Color {
         Color::White,
     ];
 
-    color_wheel.choose(&mut rand::thread_rng()).unwrap().clone()
+    *color_wheel.choose(&mut rand::thread_rng()).unwrap()
 }
 
 fn main() -> Result<()> {
@@ -143,7 +143,7 @@ fn run_app(
     terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>,
     app: &mut App,
 ) -> Result<()> {
-    Ok(loop {
+    loop {
         terminal.draw(|f| {
             ui(f, app);
         })?;
@@ -160,7 +160,7 @@ fn run_app(
         app.on_tick();
 
         terminal.clear()?;
-    })
+    }
 }
 
 fn ui(
@@ -184,7 +184,7 @@ fn ui(
     for (cpu_core, cpu_data) in app.cpu_util.iter().enumerate() {
         cpu_datasets.push(
             Dataset::default()
-                .name(format!("{}{}", "cpu", cpu_core.to_string()))
+                .name(format!("cpu{}", cpu_core))
                 .marker(symbols::Marker::Braille)
                 .style(Style::default().fg(rand_color())) 
                 .data(cpu_data),
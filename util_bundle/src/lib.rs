@@ -1,18 +1,19 @@
 // Purpose: Library for bundling system utilization data
 
-use std::process::{Command, Stdio};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use sysinfo::{System, SystemExt, CpuExt, ComponentExt, DiskExt, NetworkExt};
 use serde::{Serialize, Deserialize};
 
-fn get_nvidia_smi_output() -> Result<String, std::io::Error> {
+fn get_nvidia_smi_output(query: &str) -> Result<String, std::io::Error> {
     let output = Command::new("nvidia-smi")
-        .arg("--query-gpu=power.draw,power.limit")
+        .arg(format!("--query-gpu={}", query))
         .arg("--format=csv,noheader,nounits")
         .output()?
         .stdout;
 
-    String::from_utf8(output).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    String::from_utf8(output).map_err(std::io::Error::other)
 }
 
 fn parse_nvidia_smi_output(output: String) -> Option<(f64, f64)> {
@@ -28,8 +29,16 @@ fn parse_nvidia_smi_output(output: String) -> Option<(f64, f64)> {
     None
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+fn parse_nvidia_smi_gpu_name(output: String) -> Option<String> {
+    output.lines().next().map(|line| line.trim().to_string()).filter(|name| !name.is_empty())
+}
+
+// serde(default) lets a newer server accept bundles from older clients that lack newer fields
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct UtilBundle {
+    // when the sample was taken, milliseconds since the unix epoch (0 from clients older than protocol 1.2)
+    pub sampled_at_ms: u64,
     pub cpu_usage: Vec<f32>,
     pub cpu_temp: f32,
     pub gpu_power: f64,
@@ -40,6 +49,65 @@ pub struct UtilBundle {
     pub disk_total: u64,
     pub data_tx: u64,
     pub data_rx: u64,
+    // groups the server asked us to stop collecting, their fields are left at zero
+    pub disabled_groups: Vec<MetricGroup>,
+}
+
+// Groups of metrics a client is able to report, advertised in its HostInfo
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricGroup {
+    Cpu,
+    CpuTemp,
+    Gpu,
+    Memory,
+    Disk,
+    Network,
+    // advertised by a newer client
+    #[serde(other)]
+    Unknown,
+}
+
+// Static description of a client machine, sent once per connection as the hello message
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct HostInfo {
+    pub hostname: String,
+    pub os: String,
+    pub cpu_model: String,
+    pub core_count: usize,
+    pub mem_total: u64,
+    pub gpu_model: Option<String>,
+    pub metric_groups: Vec<MetricGroup>,
+}
+
+impl HostInfo {
+    pub fn from_sys(sys: &System) -> HostInfo {
+        let gpu_model = get_nvidia_smi_output("name").ok().and_then(parse_nvidia_smi_gpu_name);
+
+        let mut metric_groups = vec![MetricGroup::Cpu, MetricGroup::Memory, MetricGroup::Disk, MetricGroup::Network];
+        if !sys.components().is_empty() {
+            metric_groups.push(MetricGroup::CpuTemp);
+        }
+        if gpu_model.is_some() {
+            metric_groups.push(MetricGroup::Gpu);
+        }
+
+        HostInfo {
+            hostname: sys.host_name().unwrap_or_else(|| "unknown".to_string()),
+            os: sys.long_os_version().unwrap_or_else(|| "unknown".to_string()),
+            cpu_model: sys.global_cpu_info().brand().trim().to_string(),
+            core_count: sys.cpus().len(),
+            mem_total: sys.total_memory(),
+            gpu_model,
+            metric_groups,
+        }
+    }
+
+    pub fn from_refreshed_sys(sys: &mut System) -> HostInfo {
+        sys.refresh_all();
+        HostInfo::from_sys(sys)
+    }
 }
 
 // TODO: Should I make NvidiaBundle an optiopnal field of UtilBundle?
@@ -48,9 +116,16 @@ pub struct NvidiaBundle {
     pub gpu_power_limit: f64
 }
 
+impl Default for UtilBundle {
+    fn default() -> Self {
+        UtilBundle::new()
+    }
+}
+
 impl UtilBundle {
     pub fn new() -> UtilBundle {
         UtilBundle {
+            sampled_at_ms: 0,
             cpu_usage: Vec::new(),
             cpu_temp: 0.0,
             gpu_power: 0.0,
@@ -61,40 +136,61 @@ impl UtilBundle {
             disk_total: 0,
             data_tx: 0,
             data_rx: 0,
+            disabled_groups: Vec::new(),
         }
     }
 
-    fn from_sys(sys: &System) -> UtilBundle {
+    pub fn is_enabled(&self, group: MetricGroup) -> bool {
+        !self.disabled_groups.contains(&group)
+    }
+
+    fn from_sys(sys: &System, disabled_groups: &[MetricGroup]) -> UtilBundle {
         let mut bundle = UtilBundle::new();
+        bundle.disabled_groups = disabled_groups.to_vec();
 
-        bundle.cpu_usage = sys.cpus().iter().map(|x| x.cpu_usage()).collect();
+        bundle.sampled_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        if bundle.is_enabled(MetricGroup::Cpu) {
+            bundle.cpu_usage = sys.cpus().iter().map(|x| x.cpu_usage()).collect();
+        }
         // !ERROR: This returns null if program ran without sufficient perms (admin)
-        bundle.cpu_temp = sys.components().iter().map(|x| x.temperature()).sum::<f32>() / sys.components().len() as f32;
-        if let Ok(nvidia_smi_output) = get_nvidia_smi_output() {
-            if let Some((power_draw, power_max)) = parse_nvidia_smi_output(nvidia_smi_output) {
+        if bundle.is_enabled(MetricGroup::CpuTemp) {
+            bundle.cpu_temp = sys.components().iter().map(|x| x.temperature()).sum::<f32>() / sys.components().len() as f32;
+        }
+        if bundle.is_enabled(MetricGroup::Gpu) {
+            if let Some((power_draw, power_max)) = get_nvidia_smi_output("power.draw,power.limit")
+                .ok()
+                .and_then(parse_nvidia_smi_output)
+            {
                 bundle.gpu_power = power_draw;
                 bundle.gpu_power_limit = power_max;
-            } else {
-                bundle.gpu_power = 0.0;
-                bundle.gpu_power_limit = 0.0;
             }
-        } else {
-            bundle.gpu_power = 0.0;
-            bundle.gpu_power_limit = 0.0;
         }
-        bundle.mem_used = sys.used_memory();
-        bundle.mem_total = sys.total_memory();
-        bundle.disk_used = sys.disks().iter().map(|x| x.total_space() - x.available_space()).sum::<u64>();
-        bundle.disk_total = sys.disks().iter().map(|x| x.total_space()).sum::<u64>();
-        bundle.data_tx = sys.networks().into_iter().map(|(_, iface)| iface.transmitted()).sum::<u64>();
-        bundle.data_rx = sys.networks().into_iter().map(|(_, iface)| iface.received()).sum::<u64>();
+        if bundle.is_enabled(MetricGroup::Memory) {
+            bundle.mem_used = sys.used_memory();
+            bundle.mem_total = sys.total_memory();
+        }
+        if bundle.is_enabled(MetricGroup::Disk) {
+            bundle.disk_used = sys.disks().iter().map(|x| x.total_space() - x.available_space()).sum::<u64>();
+            bundle.disk_total = sys.disks().iter().map(|x| x.total_space()).sum::<u64>();
+        }
+        if bundle.is_enabled(MetricGroup::Network) {
+            bundle.data_tx = sys.networks().into_iter().map(|(_, iface)| iface.transmitted()).sum::<u64>();
+            bundle.data_rx = sys.networks().into_iter().map(|(_, iface)| iface.received()).sum::<u64>();
+        }
 
         bundle
     }
 
     pub fn from_refreshed_sys(sys: &mut System) -> UtilBundle {
+        UtilBundle::from_refreshed_sys_without(sys, &[])
+    }
+
+    pub fn from_refreshed_sys_without(sys: &mut System, disabled_groups: &[MetricGroup]) -> UtilBundle {
         sys.refresh_all();
-        UtilBundle::from_sys(&sys)
+        UtilBundle::from_sys(sys, disabled_groups)
     }
 } 
 
@@ -107,4 +203,18 @@ mod tests {
         let result = UtilBundle::new();
         assert!(result.cpu_usage.is_empty());
     }
+
+    #[test]
+    fn parse_gpu_name() {
+        let name = parse_nvidia_smi_gpu_name(" NVIDIA GeForce RTX 3080\n".to_string());
+        assert_eq!(name.as_deref(), Some("NVIDIA GeForce RTX 3080"));
+        assert_eq!(parse_nvidia_smi_gpu_name("".to_string()), None);
+    }
+
+    #[test]
+    fn host_info_tolerates_unknown_metric_groups() {
+        let info: HostInfo = serde_json::from_str(r#"{"hostname":"pc","metric_groups":["cpu","fan_speed"]}"#).unwrap();
+        assert_eq!(info.hostname, "pc");
+        assert_eq!(info.metric_groups, vec![MetricGroup::Cpu, MetricGroup::Unknown]);
+    }
 }
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+// Exponential backoff with "equal jitter": each delay is half the exponential step plus a random
+// share of the other half, so a fleet of clients doesn't reconnect in lockstep after a server restart.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff { initial, max, attempt: 0 }
+    }
+
+    // failed attempts since the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self
+            .initial
+            .checked_mul(2u32.saturating_pow(self.attempt))
+            .unwrap_or(self.max)
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = step / 2;
+        half + half.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_and_stay_capped() {
+        let initial = Duration::from_millis(100);
+        let max = Duration::from_millis(1000);
+        let mut backoff = Backoff::new(initial, max);
+
+        for attempt in 0..40 {
+            let step = (initial * 2u32.saturating_pow(attempt).min(1 << 20)).min(max);
+            let delay = backoff.next_delay();
+            assert!(delay >= step / 2 && delay <= step, "attempt {}: {:?}", attempt, delay);
+        }
+
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert!(backoff.next_delay() <= initial);
+    }
+}
@@ -0,0 +1,103 @@
+use std::io;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use pitop_protocol::{Control, EnvelopeReader, Goodbye, MessageKind, MAX_SAMPLE_INTERVAL_MILLIS, MIN_SAMPLE_INTERVAL_MILLIS};
+use util_bundle::MetricGroup;
+
+use crate::transport::Stream;
+
+// What the server currently wants from us
+pub struct SamplingSettings {
+    pub sample_interval: Duration,
+    pub disabled_groups: Vec<MetricGroup>,
+}
+
+impl SamplingSettings {
+    pub fn new(sample_interval: Duration) -> SamplingSettings {
+        SamplingSettings {
+            sample_interval,
+            disabled_groups: Vec::new(),
+        }
+    }
+
+    // Returns true if the server asked for a sample right away
+    pub fn apply(&mut self, control: Control) -> bool {
+        match control {
+            Control::SetSampleInterval { millis } => {
+                let millis = millis.clamp(MIN_SAMPLE_INTERVAL_MILLIS, MAX_SAMPLE_INTERVAL_MILLIS);
+                eprintln!("Server set sample interval to {}ms", millis);
+                self.sample_interval = Duration::from_millis(millis);
+                false
+            }
+            Control::SetMetricGroup { group, enabled } => {
+                eprintln!("Server {} {:?} metrics", if enabled { "enabled" } else { "disabled" }, group);
+                self.disabled_groups.retain(|g| *g != group);
+                if !enabled {
+                    self.disabled_groups.push(group);
+                }
+                false
+            }
+            Control::SampleNow => true,
+            Control::Unknown => false,
+        }
+    }
+}
+
+// Reads control messages off the connection until it closes. The main loop waits on the other
+// end of `controls`, so a request wakes it up instead of waiting out the sample interval.
+pub fn spawn_control_reader(mut envelopes: EnvelopeReader<Stream>, controls: Sender<Control>) {
+    thread::spawn(move || {
+        loop {
+            match envelopes.read_envelope() {
+                Ok(Some(envelope)) if envelope.kind == MessageKind::Control => {
+                    match envelope.payload() {
+                        Ok(control) => {
+                            if controls.send(control).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => eprintln!("Dropping malformed control message: {}", e),
+                    }
+                }
+                // the connection closes right after, the main loop reconnects as usual
+                Ok(Some(envelope)) if envelope.kind == MessageKind::Goodbye => {
+                    let reason = envelope.payload::<Goodbye>().map(|goodbye| goodbye.reason).unwrap_or_default();
+                    eprintln!("Server is going away: {}", reason);
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => return,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => eprintln!("Dropping frame: {}", e),
+                // the main loop notices a dead connection on its next write
+                Err(_) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_is_clamped() {
+        let mut settings = SamplingSettings::new(Duration::from_millis(250));
+        settings.apply(Control::SetSampleInterval { millis: 1 });
+        assert_eq!(settings.sample_interval, Duration::from_millis(MIN_SAMPLE_INTERVAL_MILLIS));
+        settings.apply(Control::SetSampleInterval { millis: 100 });
+        assert_eq!(settings.sample_interval, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn metric_groups_toggle_without_duplicates() {
+        let mut settings = SamplingSettings::new(Duration::from_millis(250));
+        settings.apply(Control::SetMetricGroup { group: MetricGroup::Gpu, enabled: false });
+        settings.apply(Control::SetMetricGroup { group: MetricGroup::Gpu, enabled: false });
+        assert_eq!(settings.disabled_groups, vec![MetricGroup::Gpu]);
+
+        settings.apply(Control::SetMetricGroup { group: MetricGroup::Gpu, enabled: true });
+        assert!(settings.disabled_groups.is_empty());
+        assert!(settings.apply(Control::SampleNow));
+    }
+}
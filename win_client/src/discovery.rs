@@ -0,0 +1,120 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pitop_protocol::discovery::{BeaconListener, DISCOVERY_PORT};
+
+use crate::transport::{Endpoint, Transport};
+
+// beacons go out every second, a server silent for longer than this is taken to be gone
+const DISCOVERY_TIMEOUT_MILLIS: u64 = 3_000;
+
+// The newest matching beacon and when it was heard
+type Latest = Arc<Mutex<Option<(Instant, Endpoint)>>>;
+
+// Where to connect: the endpoint given on the command line, or whichever server announces itself
+pub enum Server {
+    Fixed(Endpoint),
+    Discovered { latest: Latest, name: Option<String> },
+}
+
+impl Server {
+    pub fn discover(name: Option<String>, transport: Transport) -> io::Result<Server> {
+        Ok(Server::listen(BeaconListener::bind(DISCOVERY_PORT)?, name, transport))
+    }
+
+    // Beacons are listened for in the background the whole time, so locating a server never waits
+    fn listen(listener: BeaconListener, name: Option<String>, transport: Transport) -> Server {
+        let latest: Latest = Arc::new(Mutex::new(None));
+        let heard = Arc::clone(&latest);
+        let wanted = name.clone();
+        thread::spawn(move || {
+            let transport = transport.name();
+            loop {
+                let found = listener.wait(Duration::from_millis(DISCOVERY_TIMEOUT_MILLIS), |beacon| {
+                    beacon.transport == transport && wanted.as_ref().is_none_or(|name| *name == beacon.name)
+                });
+                match found {
+                    Ok(Some((addr, beacon))) => {
+                        let Ok(mut latest) = heard.lock() else { return };
+                        let addr = addr.to_string();
+                        if latest.as_ref().is_none_or(|(_, known)| known.to_string() != addr) {
+                            eprintln!("Found {} at {}", beacon.name, addr);
+                        }
+                        *latest = Some((Instant::now(), Endpoint::Net { addr, host: beacon.name }));
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        eprintln!("Failed to listen for beacons: {}", e);
+                        thread::sleep(Duration::from_millis(DISCOVERY_TIMEOUT_MILLIS));
+                    }
+                }
+            }
+        });
+        Server::Discovered { latest, name }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Server::Fixed(endpoint) => endpoint.to_string(),
+            Server::Discovered { name: Some(name), .. } => format!("server {}", name),
+            Server::Discovered { name: None, .. } => "any server".to_string(),
+        }
+    }
+
+    pub fn can_reconnect(&self) -> bool {
+        match self {
+            Server::Fixed(endpoint) => endpoint.can_reopen(),
+            Server::Discovered { .. } => true,
+        }
+    }
+
+    // Whichever server was heard from last, so a server that moved is found again
+    pub fn locate(&self) -> io::Result<Endpoint> {
+        match self {
+            Server::Fixed(endpoint) => Ok(endpoint.clone()),
+            Server::Discovered { latest, .. } => {
+                let latest = latest.lock().map_err(|_| io::Error::other("beacon listener panicked"))?;
+                match &*latest {
+                    Some((heard_at, endpoint)) if heard_at.elapsed() < Duration::from_millis(DISCOVERY_TIMEOUT_MILLIS) => {
+                        Ok(endpoint.clone())
+                    }
+                    _ => Err(io::Error::new(io::ErrorKind::NotFound, format!("no beacon from {}", self.describe()))),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitop_protocol::discovery::{Beacon, BeaconSender};
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    #[test]
+    fn locate_answers_right_away_with_the_latest_beacon() {
+        let listener = BeaconListener::bind(0).unwrap();
+        let target = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listener.local_addr().unwrap().port());
+        let server = Server::listen(listener, Some("desk-pi".to_string()), Transport::Tcp);
+
+        let started = Instant::now();
+        assert_eq!(server.locate().err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        let mut sender = BeaconSender::new(target).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let endpoint = loop {
+            for name in ["lab-pi", "desk-pi"] {
+                sender.send(&Beacon { name: name.to_string(), port: 7878, transport: "tcp".to_string(), ..Beacon::default() }).unwrap();
+            }
+            if let Ok(endpoint) = server.locate() {
+                break endpoint;
+            }
+            assert!(Instant::now() < deadline, "no beacon heard");
+            thread::sleep(Duration::from_millis(20));
+        };
+        assert_eq!(endpoint.to_string(), "127.0.0.1:7878");
+    }
+}
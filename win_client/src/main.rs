@@ -1,21 +1,43 @@
-use std::str;
-use std::net::TcpStream;
-use std::thread;
-use std::time;
-use std::io::{self, prelude::*, BufReader, Write};
+mod backoff;
+mod control;
+mod discovery;
+mod spool;
+mod transport;
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::time::{self, Instant};
+use std::io;
 
 use sysinfo::{System, SystemExt};
 use clap::Parser;
+use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::flag;
+
+use util_bundle::{HostInfo, UtilBundle};
+use pitop_protocol::auth::{self, PreSharedKey};
+use pitop_protocol::{Control, EnvelopeReader, EnvelopeWriter, Goodbye, MessageKind};
 
-use util_bundle::UtilBundle;
+use crate::backoff::Backoff;
+use crate::control::{spawn_control_reader, SamplingSettings};
+use crate::discovery::Server;
+use crate::spool::Spool;
+use crate::transport::{Endpoint, Stream, TlsSettings, Transport};
 
 const POLLING_PERIOD_MILLIS: u64 = 250;
 
-fn send_newline_delimited_json(stream: &mut TcpStream, json_bundle: String) -> io::Result<()> {
-    stream.write_all(json_bundle.as_bytes()).expect("Failed to write");
-    stream.write_all(b"\n").expect("Failed to write");
-    stream.flush()
-}
+const INITIAL_BACKOFF_MILLIS: u64 = 500;
+const MAX_BACKOFF_MILLIS: u64 = 30_000;
+// a connection has to stay up this long before the backoff starts over
+const HEALTHY_CONNECTION_MILLIS: u64 = 10_000;
+const HEARTBEAT_PERIOD_MILLIS: u64 = 1_000;
+const AUTH_TIMEOUT_MILLIS: u64 = 5_000;
+// over UDP the hello can get lost like any other datagram, so it is repeated
+const HELLO_RESEND_MILLIS: u64 = 10_000;
+// however long the sample interval, Ctrl-C is noticed within this long
+const SHUTDOWN_POLL_MILLIS: u64 = 100;
 
 #[derive(Parser)]
 #[command(name = "PiTop Windows Client")]
@@ -23,35 +45,264 @@ fn send_newline_delimited_json(stream: &mut TcpStream, json_bundle: String) -> i
 #[command(version = "1.0")]
 #[command(about = "Provides system utilization to the PiTop Server", long_about = None)]
 struct Args {
-    #[arg(short, long, default_value = "127.0.0.1")]
-    ip: String,
-    
+    /// Server address, without it the client waits for a server's discovery beacon
+    #[arg(short, long)]
+    ip: Option<String>,
+
+    /// Only connect to the discovered server announcing this name
+    #[arg(long, conflicts_with = "ip")]
+    server_name: Option<String>,
+
+    /// Write frames to stdout instead of connecting, e.g. `ssh host win_client --stdout | pi_server --stdin`
+    #[arg(long, conflicts_with_all = ["ip", "server_name", "tls_ca", "psk_file"])]
+    stdout: bool,
+
+    /// Send frames over this serial device (e.g. COM3 or /dev/ttyACM0) instead of the network
+    #[arg(long, conflicts_with_all = ["ip", "server_name", "stdout", "transport", "tls_ca", "psk_file"])]
+    serial: Option<PathBuf>,
+
+    /// Baud rate for --serial
+    #[arg(long, default_value = "115200", requires = "serial")]
+    baud: u32,
+
+    /// Connect to a server on this machine through its Unix domain socket (`pi_server --unix`)
+    #[cfg(unix)]
+    #[arg(long, conflicts_with_all = ["ip", "server_name", "stdout", "serial", "transport", "tls_ca"])]
+    unix: Option<PathBuf>,
+
     #[arg(short, long, default_value = "7878")]
-    port: String
+    port: String,
+
+    #[arg(long, value_enum, default_value = "tcp")]
+    transport: Transport,
+
+    /// Samples kept in memory while the server is unreachable (2400 is 10 minutes)
+    #[arg(long, default_value = "2400")]
+    spool_capacity: usize,
+
+    /// Spill samples that overflow the in-memory spool to this file
+    #[arg(long)]
+    spool_file: Option<PathBuf>,
+
+    #[arg(long, default_value = "67108864")]
+    spool_max_bytes: u64,
+
+    /// Connect over TLS, trusting server certificates signed by this CA (PEM)
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+
+    /// Client certificate (PEM) for servers that require mutual TLS
+    #[arg(long, requires = "tls_ca", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Name to check the server certificate against, defaults to --ip or the discovered server's name
+    #[arg(long, requires = "tls_ca")]
+    tls_server_name: Option<String>,
+
+    /// Authenticate to the server with the pre-shared key in this file
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+}
+
+fn connect(endpoint: &Endpoint, transport: Transport, tls: Option<&TlsSettings>, psk: Option<&PreSharedKey>, sys: &mut System, controls: &Sender<Control>) -> io::Result<EnvelopeWriter<Stream>> {
+    let stream = Stream::open(endpoint, transport, tls)?;
+    // a serial line can garble bytes, the other transports deliver them intact or not at all
+    let mut incoming = match endpoint {
+        Endpoint::Serial { .. } => EnvelopeReader::resyncing(stream.try_clone()?),
+        _ => EnvelopeReader::new(stream.try_clone()?),
+    };
+    let mut envelopes = EnvelopeWriter::new(stream);
+    if let Some(key) = psk {
+        // a server without a key never sends the challenge, don't wait on it forever
+        incoming.get_ref().set_read_timeout(Some(time::Duration::from_millis(AUTH_TIMEOUT_MILLIS)))?;
+        auth::answer_challenge(&mut incoming, &mut envelopes, key)?;
+        incoming.get_ref().set_read_timeout(None)?;
+    }
+    spawn_control_reader(incoming, controls.clone());
+
+    // let the server know which machine it is watching before any samples arrive
+    envelopes.send(MessageKind::Hello, &HostInfo::from_refreshed_sys(sys))?;
+    Ok(envelopes)
+}
+
+// Shuts a broken connection down, which also ends its control reader thread, and schedules the
+// next attempt by the backoff, so a server that accepts and then hangs up (full, wrong key,
+// shutting down) isn't retried every sample
+fn disconnect(connection: &mut Option<EnvelopeWriter<Stream>>, addr: &str, reason: io::Error, backoff: &mut Backoff, next_attempt: &mut Instant) {
+    if let Some(mut envelopes) = connection.take() {
+        let delay = backoff.next_delay();
+        eprintln!("Lost connection to {}: {} (reconnecting in {:.1}s)", addr, reason, delay.as_secs_f64());
+        let _ = envelopes.get_mut().shutdown();
+        *next_attempt = Instant::now() + delay;
+    }
 }
 
 fn main() -> io::Result<()> {
     let args = Args::parse();
-    println!("Win Client is running...");
+    // status goes to stderr, stdout may be carrying the frames
+    eprintln!("Win Client is running...");
 
+    let server = match (&args.serial, &args.ip) {
+        _ if args.stdout => Server::Fixed(Endpoint::Stdout),
+        #[cfg(unix)]
+        _ if args.unix.is_some() => Server::Fixed(Endpoint::Unix(args.unix.clone().unwrap())),
+        (Some(path), _) => Server::Fixed(Endpoint::Serial { path: path.clone(), baud: args.baud }),
+        (None, Some(ip)) => Server::Fixed(Endpoint::Net { addr: format!("{}:{}", ip, args.port), host: ip.clone() }),
+        (None, None) => Server::discover(args.server_name.clone(), args.transport)?,
+    };
+    // what the logs call the server, its address once connected
+    let mut addr = server.describe();
+    let tls = match &args.tls_ca {
+        Some(ca) => {
+            let cert_and_key = args.tls_cert.as_deref().zip(args.tls_key.as_deref());
+            Some(TlsSettings::load(ca, cert_and_key, args.tls_server_name.clone())?)
+        }
+        None => None,
+    };
+    let psk = args.psk_file.as_deref().map(PreSharedKey::load).transpose()?;
+    if args.transport == Transport::Udp && (tls.is_some() || psk.is_some()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "TLS and pre-shared keys need --transport tcp"));
+    }
     let mut sys = System::new_all();
-    let mut stream = TcpStream::connect(format!("{}:{}", args.ip, args.port))?;
-    // TODO: Use cntrl-c crate for graceful exit?
-    loop {
-        let bundle: UtilBundle = UtilBundle::from_refreshed_sys(&mut sys);
+    let mut connection: Option<EnvelopeWriter<Stream>> = None;
+    let mut backoff = Backoff::new(
+        time::Duration::from_millis(INITIAL_BACKOFF_MILLIS),
+        time::Duration::from_millis(MAX_BACKOFF_MILLIS),
+    );
+    let mut next_attempt = Instant::now();
+    let mut connected_at = Instant::now();
+    let mut last_sent = Instant::now();
+    let mut last_hello = Instant::now();
+    let mut ever_connected = false;
+    let mut spool = match args.spool_file {
+        Some(path) => Spool::with_disk(args.spool_capacity, path, args.spool_max_bytes),
+        None => Spool::new(args.spool_capacity),
+    };
+    let mut settings = SamplingSettings::new(time::Duration::from_millis(POLLING_PERIOD_MILLIS));
+    let (controls_tx, controls) = channel();
+    let heartbeat_period = time::Duration::from_millis(HEARTBEAT_PERIOD_MILLIS);
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    for signal in TERM_SIGNALS {
+        // a second Ctrl-C while we are still saying goodbye exits right away
+        flag::register_conditional_shutdown(*signal, 1, Arc::clone(&shutting_down))?;
+        flag::register(*signal, Arc::clone(&shutting_down))?;
+    }
+    while !shutting_down.load(Ordering::SeqCst) {
+        let sampled_at = Instant::now();
+        let bundle: UtilBundle = UtilBundle::from_refreshed_sys_without(&mut sys, &settings.disabled_groups);
         // println!("{}", serde_json::to_string_pretty(&bundle).unwrap());
-        let json_bundle = serde_json::to_string(&bundle).unwrap();
 
-        // Source https://www.wikiwand.com/en/Line_Delimited_JSON
-        send_newline_delimited_json(&mut stream, json_bundle)?;
+        // keep sampling while disconnected, reconnect attempts are spaced out by the backoff
+        if connection.is_none() && Instant::now() >= next_attempt {
+            if ever_connected && !server.can_reconnect() {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, format!("{} was closed", addr)));
+            }
+            eprintln!("Connecting to {} (attempt {})...", addr, backoff.attempt() + 1);
+            let connected = server.locate().and_then(|endpoint| {
+                addr = endpoint.to_string();
+                connect(&endpoint, args.transport, tls.as_ref(), psk.as_ref(), &mut sys, &controls_tx)
+            });
+            match connected {
+                Ok(envelopes) => {
+                    eprintln!("Connected to {}", addr);
+                    ever_connected = true;
+                    connected_at = Instant::now();
+                    connection = Some(envelopes);
+                    last_hello = Instant::now();
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    eprintln!("Failed to connect to {}: {} (retrying in {:.1}s)", addr, e, delay.as_secs_f64());
+                    next_attempt = Instant::now() + delay;
+                }
+            }
+        }
+
+        // Each bundle is wrapped in a versioned envelope and sent as one length-prefixed frame.
+        // Anything sampled during an outage is replayed first, oldest to newest.
+        let sent = match &mut connection {
+            Some(envelopes) if !spool.is_empty() => spool
+                .replay(|spooled| envelopes.send(MessageKind::Sample, spooled))
+                .and_then(|replayed| {
+                    eprintln!("Replayed {} spooled samples", replayed);
+                    envelopes.send(MessageKind::Sample, &bundle)
+                }),
+            Some(envelopes) => envelopes.send(MessageKind::Sample, &bundle),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "not connected")),
+        };
+        match sent {
+            Ok(()) => last_sent = Instant::now(),
+            Err(e) => {
+                disconnect(&mut connection, &addr, e, &mut backoff, &mut next_attempt);
+                let dropped = spool.dropped();
+                spool.push(bundle);
+                if spool.dropped() > dropped {
+                    eprintln!("Spool full, {} samples dropped so far", spool.dropped());
+                }
+            }
+        }
+
+        if connection.is_some() && connected_at.elapsed() >= time::Duration::from_millis(HEALTHY_CONNECTION_MILLIS) {
+            backoff.reset();
+        }
+
+        if args.transport == Transport::Udp && last_hello.elapsed() >= time::Duration::from_millis(HELLO_RESEND_MILLIS) {
+            if let Some(envelopes) = &mut connection {
+                match envelopes.send(MessageKind::Hello, &HostInfo::from_sys(&sys)) {
+                    Ok(()) => last_hello = Instant::now(),
+                    Err(e) => disconnect(&mut connection, &addr, e, &mut backoff, &mut next_attempt),
+                }
+            }
+        }
 
-        let mut reader = BufReader::new(&stream);
-        // TODO: Use debug levels & use a logging crate
-        // let mut buffer: Vec<u8> = Vec::new();
-        // let bytes_returned = reader.read_until( b'\n', &mut buffer)?;
+        // Wait for the next sample. Control requests from the server wake us early, and samples
+        // double as heartbeats so one only goes out when the interval is longer than the heartbeat period.
+        loop {
+            let now = Instant::now();
+            let next_sample = sampled_at + settings.sample_interval;
+            if now >= next_sample || shutting_down.load(Ordering::SeqCst) {
+                break;
+            }
 
-        // if bytes_returned > 0 { println!("read from server: {}", str::from_utf8(&buffer).unwrap()); }
-        thread::sleep(time::Duration::from_millis(POLLING_PERIOD_MILLIS));
+            let mut wake_at = next_sample;
+            if let Some(envelopes) = &mut connection {
+                if now >= last_sent + heartbeat_period {
+                    match envelopes.send(MessageKind::Heartbeat, &()) {
+                        Ok(()) => last_sent = now,
+                        Err(e) => disconnect(&mut connection, &addr, e, &mut backoff, &mut next_attempt),
+                    }
+                    continue;
+                }
+                wake_at = wake_at.min(last_sent + heartbeat_period);
+            }
+
+            // TODO: Use debug levels & use a logging crate
+            let wake_at = wake_at.min(now + time::Duration::from_millis(SHUTDOWN_POLL_MILLIS));
+            if let Ok(control) = controls.recv_timeout(wake_at - now) {
+                if settings.apply(control) {
+                    break;
+                }
+            }
+        }
+    }
+
+    // the server shows a client that said goodbye as gone rather than lost
+    eprintln!("Shutting down...");
+    if let Some(mut envelopes) = connection.take() {
+        let goodbye = Goodbye { reason: "client shutting down".to_string() };
+        if let Err(e) = envelopes.send(MessageKind::Goodbye, &goodbye) {
+            eprintln!("Failed to say goodbye to {}: {}", addr, e);
+        }
+        let _ = envelopes.get_mut().shutdown();
+    }
+    match spool.persist() {
+        Ok(0) => {}
+        Ok(saved) => eprintln!("Saved {} spooled samples for the next run", saved),
+        Err(e) => eprintln!("Failed to save spooled samples: {}", e),
     }
     Ok(())
 }
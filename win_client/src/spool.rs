@@ -0,0 +1,279 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use util_bundle::UtilBundle;
+
+// Holds samples taken while the server is unreachable so they can be replayed after reconnecting.
+// The newest samples live in a bounded in-memory ring. With a spool file configured, samples
+// evicted from the ring are appended to it (one JSON bundle per line) instead of being dropped,
+// so the file always holds the oldest part of the backlog.
+pub struct Spool {
+    ring: VecDeque<UtilBundle>,
+    capacity: usize,
+    disk: Option<DiskSpool>,
+    // the spool file couldn't be written, only the ring is used until a replay empties the file
+    disk_failed: bool,
+    dropped: u64,
+}
+
+struct DiskSpool {
+    path: PathBuf,
+    max_bytes: u64,
+    // size of the file, kept here rather than asking the filesystem on every sample
+    len: u64,
+}
+
+impl DiskSpool {
+    fn new(path: PathBuf, max_bytes: u64) -> DiskSpool {
+        let len = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        DiskSpool { path, max_bytes, len }
+    }
+
+    fn append(&mut self, bundle: &UtilBundle) -> io::Result<bool> {
+        Ok(self.append_all([bundle])? == 1)
+    }
+
+    // Appends bundles in order until the file is full, with a single sync. Returns how many fit.
+    fn append_all<'a, I: IntoIterator<Item = &'a UtilBundle>>(&mut self, bundles: I) -> io::Result<usize> {
+        let mut room = self.max_bytes.saturating_sub(self.len);
+        let mut lines = Vec::new();
+        let mut count = 0;
+        for bundle in bundles {
+            let mut line = serde_json::to_vec(bundle)?;
+            line.push(b'\n');
+            if line.len() as u64 > room {
+                break;
+            }
+            room -= line.len() as u64;
+            lines.extend_from_slice(&line);
+            count += 1;
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(&lines)?;
+        file.sync_data()?;
+        self.len += lines.len() as u64;
+        Ok(count)
+    }
+
+    fn load(&self) -> io::Result<Vec<UtilBundle>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut bundles = Vec::new();
+        for line in BufReader::new(file).lines() {
+            // a torn last line from a crash mid-write is simply skipped
+            if let Ok(bundle) = serde_json::from_str(&line?) {
+                bundles.push(bundle);
+            }
+        }
+        Ok(bundles)
+    }
+
+    fn store(&mut self, bundles: &[UtilBundle]) -> io::Result<()> {
+        let mut contents = Vec::new();
+        for bundle in bundles {
+            serde_json::to_writer(&mut contents, bundle)?;
+            contents.push(b'\n');
+        }
+        fs::write(&self.path, &contents)?;
+        self.len = contents.len() as u64;
+        Ok(())
+    }
+}
+
+impl Spool {
+    pub fn new(capacity: usize) -> Spool {
+        Spool {
+            ring: VecDeque::with_capacity(capacity),
+            capacity,
+            disk: None,
+            disk_failed: false,
+            dropped: 0,
+        }
+    }
+
+    // Leftovers from a previous run already in the file are replayed too
+    pub fn with_disk(capacity: usize, path: PathBuf, max_bytes: u64) -> Spool {
+        let mut spool = Spool::new(capacity);
+        spool.disk = Some(DiskSpool::new(path, max_bytes));
+        spool
+    }
+
+    // samples lost because both the ring and the spool file were full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty() && self.disk.as_ref().is_none_or(|disk| disk.len == 0)
+    }
+
+    // A spool file that can't be written (disk full, permissions) is given up on with a message,
+    // whatever it would have held counts as dropped
+    pub fn push(&mut self, bundle: UtilBundle) {
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+
+        if self.ring.len() == self.capacity {
+            let oldest = self.ring.pop_front().unwrap();
+            let spilled = match &mut self.disk {
+                Some(disk) if !self.disk_failed => disk.append(&oldest).unwrap_or_else(|e| {
+                    eprintln!("Failed to spool to {}: {}, keeping only the newest {} samples", disk.path.display(), e, self.capacity);
+                    self.disk_failed = true;
+                    false
+                }),
+                _ => false,
+            };
+            if !spilled {
+                self.dropped += 1;
+            }
+        }
+        self.ring.push_back(bundle);
+    }
+
+    // Moves the in-memory backlog to the end of the spool file on the way out, so the next run
+    // replays it. Returns how many samples were saved, none without a spool file.
+    pub fn persist(&mut self) -> io::Result<usize> {
+        let Some(disk) = &mut self.disk else { return Ok(0) };
+        let saved = disk.append_all(&self.ring)?;
+        self.dropped += (self.ring.len() - saved) as u64;
+        self.ring.clear();
+        Ok(saved)
+    }
+
+    // Sends the backlog oldest first. Stops at the first failed send, keeping that bundle and
+    // everything after it spooled. Returns how many bundles were sent.
+    pub fn replay<F: FnMut(&UtilBundle) -> io::Result<()>>(&mut self, mut send: F) -> io::Result<usize> {
+        let mut sent = 0;
+
+        if let Some(disk) = &mut self.disk {
+            let backlog = disk.load()?;
+            for (idx, bundle) in backlog.iter().enumerate() {
+                if let Err(e) = send(bundle) {
+                    disk.store(&backlog[idx..])?;
+                    return Err(e);
+                }
+                sent += 1;
+            }
+            if !backlog.is_empty() || disk.len > 0 {
+                disk.store(&[])?;
+            }
+            self.disk_failed = false;
+        }
+
+        while let Some(bundle) = self.ring.front() {
+            send(bundle)?;
+            self.ring.pop_front();
+            sent += 1;
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(sampled_at_ms: u64) -> UtilBundle {
+        let mut bundle = UtilBundle::new();
+        bundle.sampled_at_ms = sampled_at_ms;
+        bundle
+    }
+
+    fn replay_all(spool: &mut Spool) -> Vec<u64> {
+        let mut sent = Vec::new();
+        spool
+            .replay(|b| {
+                sent.push(b.sampled_at_ms);
+                Ok(())
+            })
+            .unwrap();
+        sent
+    }
+
+    #[test]
+    fn ring_drops_oldest_when_full() {
+        let mut spool = Spool::new(3);
+        for t in 0..5 {
+            spool.push(bundle(t));
+        }
+
+        assert_eq!(spool.dropped(), 2);
+        assert_eq!(replay_all(&mut spool), vec![2, 3, 4]);
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    fn overflow_spills_to_disk_and_replays_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = Spool::with_disk(2, dir.path().join("spool"), 1 << 20);
+        for t in 0..6 {
+            spool.push(bundle(t));
+        }
+
+        assert_eq!(spool.dropped(), 0);
+        assert_eq!(replay_all(&mut spool), vec![0, 1, 2, 3, 4, 5]);
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    fn persisted_backlog_is_replayed_by_the_next_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool");
+        let mut spool = Spool::with_disk(2, path.clone(), 1 << 20);
+        for t in 0..5 {
+            spool.push(bundle(t));
+        }
+        assert_eq!(spool.persist().unwrap(), 2);
+        drop(spool);
+
+        let mut next_run = Spool::with_disk(2, path, 1 << 20);
+        assert!(!next_run.is_empty());
+        assert_eq!(replay_all(&mut next_run), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn unwritable_spool_file_falls_back_on_the_ring() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = Spool::with_disk(2, dir.path().join("missing").join("spool"), 1 << 20);
+        for t in 0..5 {
+            spool.push(bundle(t));
+        }
+
+        assert_eq!(spool.dropped(), 3);
+        assert_eq!(replay_all(&mut spool), vec![3, 4]);
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    fn failed_replay_keeps_unsent_bundles() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = Spool::with_disk(1, dir.path().join("spool"), 1 << 20);
+        for t in 0..4 {
+            spool.push(bundle(t));
+        }
+
+        let mut budget = 2;
+        let result = spool.replay(|_| {
+            if budget == 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+            }
+            budget -= 1;
+            Ok(())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(replay_all(&mut spool), vec![2, 3]);
+    }
+}
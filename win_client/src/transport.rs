@@ -0,0 +1,247 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use pitop_protocol::datagram::DatagramStream;
+use pitop_protocol::serial::SerialPort;
+use pitop_protocol::tls::{self, ClientConfig, TlsStream};
+
+const CONNECT_TIMEOUT_MILLIS: u64 = 3_000;
+// a dead peer should fail our writes instead of blocking the sampling loop
+const WRITE_TIMEOUT_MILLIS: u64 = 5_000;
+// UDP sockets and serial ports can't be shut down, their readers check for a closed link this
+// often instead
+const POLL_MILLIS: u64 = 500;
+
+pub struct TlsSettings {
+    config: Arc<ClientConfig>,
+    // overrides the host name the server was reached by
+    server_name: Option<String>,
+}
+
+impl TlsSettings {
+    // Loaded once at startup so a bad certificate path fails right away instead of on every retry
+    pub fn load(ca: &Path, cert_and_key: Option<(&Path, &Path)>, server_name: Option<String>) -> io::Result<TlsSettings> {
+        Ok(TlsSettings { config: tls::client_config(ca, cert_and_key)?, server_name })
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Debug)]
+pub enum Transport {
+    Tcp,
+    // one datagram per message, a lossy link drops samples instead of stalling
+    Udp,
+}
+
+impl Transport {
+    // as spelled on the command line and in beacons
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transport::Tcp => "tcp",
+            Transport::Udp => "udp",
+        }
+    }
+}
+
+// Somewhere samples can be sent, resolved fresh for every connection attempt
+#[derive(Clone)]
+pub enum Endpoint {
+    // host is what a TLS server certificate is checked against
+    Net { addr: String, host: String },
+    // frames go to our own stdout, e.g. piped through ssh into `pi_server --stdin`
+    Stdout,
+    // a UART or USB serial gadget cabled to the server
+    Serial { path: PathBuf, baud: u32 },
+    // a server on this machine listening with `pi_server --unix`
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    // stdout can't be opened again once whoever read it has gone away, a serial device that
+    // vanished may come back when the cable is plugged in again
+    pub fn can_reopen(&self) -> bool {
+        !matches!(self, Endpoint::Stdout)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Net { addr, .. } => write!(f, "{}", addr),
+            Endpoint::Stdout => write!(f, "stdout"),
+            Endpoint::Serial { path, .. } => write!(f, "{}", path.display()),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+// A connection to the server, plaintext or TLS over TCP, a connected UDP socket, stdout, a
+// serial line or a local Unix socket
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+    // the flag is shared by clones and set on shutdown
+    Udp(DatagramStream, Arc<AtomicBool>),
+    // write only, reads see end of stream so nobody waits for control messages
+    Stdout(io::Stdout),
+    // shares its closed flag like Udp
+    Serial(SerialPort, Arc<AtomicBool>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn open(endpoint: &Endpoint, transport: Transport, tls: Option<&TlsSettings>) -> io::Result<Stream> {
+        let (addr, host) = match endpoint {
+            Endpoint::Net { addr, host } => (addr, host),
+            Endpoint::Stdout => return Ok(Stream::Stdout(io::stdout())),
+            Endpoint::Serial { path, baud } => return Stream::open_serial(path, *baud),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let sock = UnixStream::connect(path)?;
+                sock.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MILLIS)))?;
+                return Ok(Stream::Unix(sock));
+            }
+        };
+
+        // resolve on every attempt, the server's address may have changed while we were away
+        let socket_addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", addr)))?;
+        if transport == Transport::Udp {
+            return Stream::open_udp(socket_addr);
+        }
+
+        let sock = TcpStream::connect_timeout(&socket_addr, Duration::from_millis(CONNECT_TIMEOUT_MILLIS))?;
+        sock.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MILLIS)))?;
+
+        match tls {
+            Some(tls) => {
+                let server_name = tls.server_name.as_deref().unwrap_or(host);
+                Ok(Stream::Tls(TlsStream::connect(tls.config.clone(), server_name, sock)?))
+            }
+            None => Ok(Stream::Plain(sock)),
+        }
+    }
+
+    fn open_udp(server: SocketAddr) -> io::Result<Stream> {
+        let local: SocketAddr = if server.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(local)?;
+        // a connected socket only hears from the server and reports it unreachable on send
+        socket.connect(server)?;
+        socket.set_read_timeout(Some(Duration::from_millis(POLL_MILLIS)))?;
+        Ok(Stream::Udp(DatagramStream::connected(socket), Arc::new(AtomicBool::new(false))))
+    }
+
+    fn open_serial(path: &Path, baud: u32) -> io::Result<Stream> {
+        let mut port = SerialPort::open(path, baud)?;
+        port.set_read_timeout(Some(Duration::from_millis(POLL_MILLIS)))?;
+        Ok(Stream::Serial(port, Arc::new(AtomicBool::new(false))))
+    }
+
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Plain(sock) => sock.try_clone().map(Stream::Plain),
+            Stream::Tls(stream) => stream.try_clone().map(Stream::Tls),
+            Stream::Udp(stream, closed) => Ok(Stream::Udp(stream.try_clone()?, Arc::clone(closed))),
+            Stream::Stdout(_) => Ok(Stream::Stdout(io::stdout())),
+            Stream::Serial(port, closed) => {
+                let mut port = port.try_clone()?;
+                port.set_read_timeout(Some(Duration::from_millis(POLL_MILLIS)))?;
+                Ok(Stream::Serial(port, Arc::clone(closed)))
+            }
+            #[cfg(unix)]
+            Stream::Unix(sock) => sock.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Plain(sock) => sock.set_read_timeout(timeout),
+            Stream::Tls(stream) => stream.get_ref().set_read_timeout(timeout),
+            Stream::Udp(stream, _) => stream.get_ref().set_read_timeout(timeout),
+            Stream::Stdout(_) => Ok(()),
+            // it has to keep polling for shutdown
+            Stream::Serial(..) => Err(io::Error::new(io::ErrorKind::Unsupported, "serial ports keep their own read timeout")),
+            #[cfg(unix)]
+            Stream::Unix(sock) => sock.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Plain(sock) => sock.shutdown(Shutdown::Both),
+            Stream::Tls(stream) => stream.shutdown(),
+            Stream::Udp(_, closed) | Stream::Serial(_, closed) => {
+                closed.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+            Stream::Stdout(_) => Ok(()),
+            #[cfg(unix)]
+            Stream::Unix(sock) => sock.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(sock) => sock.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+            Stream::Udp(stream, closed) => read_until_closed(stream, closed, buf),
+            Stream::Stdout(_) => Ok(0),
+            Stream::Serial(port, closed) => read_until_closed(port, closed, buf),
+            #[cfg(unix)]
+            Stream::Unix(sock) => sock.read(buf),
+        }
+    }
+}
+
+// Reads with a timeout, reporting end of stream once the closed flag is set
+fn read_until_closed<R: Read>(inner: &mut R, closed: &AtomicBool, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match inner.read(buf) {
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if closed.load(Ordering::SeqCst) {
+                    return Ok(0);
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(sock) => sock.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+            Stream::Udp(stream, _) => stream.write(buf),
+            Stream::Stdout(stdout) => stdout.write(buf),
+            Stream::Serial(port, _) => port.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(sock) => sock.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(sock) => sock.flush(),
+            Stream::Tls(stream) => stream.flush(),
+            Stream::Udp(stream, _) => stream.flush(),
+            Stream::Stdout(stdout) => stdout.flush(),
+            Stream::Serial(port, _) => port.flush(),
+            #[cfg(unix)]
+            Stream::Unix(sock) => sock.flush(),
+        }
+    }
+}